@@ -1,36 +1,353 @@
 use crate::errors::ErrorType;
 use crate::container::Container;
+use crate::config::IdMapping;
+use crate::cgroup_v2::{self, CGROUP_ROOT};
+use crate::ipc::{Message, send_message, recv_message, set_recv_timeout, unexpected_message, HANDSHAKE_TIMEOUT};
+use crate::utils::parse_size;
+use crate::ResourceOptions;
 
 use cgroups_rs::cgroup_builder::CgroupBuilder;
-use cgroups_rs::{MaxValue, CgroupPid};
-use rlimit::{setrlimit, Resource};
+use cgroups_rs::hierarchies::{is_cgroup2_unified_mode, V1};
+use cgroups_rs::MaxValue;
+use nix::errno::Errno;
+use nix::sched::{unshare, CloneFlags};
+use nix::sys::signal::{kill, Signal};
+use nix::sys::stat::{major, minor, stat, SFlag};
+use nix::unistd::Pid;
 
-use std::fs::{canonicalize, remove_dir};
-use std::convert::TryInto;
+use std::fs::{canonicalize, read_dir, read_to_string, remove_dir, write};
+use std::io::ErrorKind;
+use std::path::{Component, Path, PathBuf};
+use std::thread::sleep;
+use std::time::Duration;
 
-const KMEM_LIMIT: i64 = 1024 * 1024 * 1024;
-const MEM_LIMIT: i64 = KMEM_LIMIT;
-const MAX_PID: MaxValue = MaxValue::Value(64);
-const NOFILE_RLIMIT: u64 = 64;
+const MEM_LIMIT: i64 = 1024 * 1024 * 1024;
+const MAX_PID: i64 = 64;
+const CPU_SHARES: u64 = 256;
+const BLKIO_WEIGHT: u16 = 50;
+pub const CPU_PERIOD: u64 = 100000;
+const CPU_SHARES_RANGE: (u64, u64) = (2, 262144);
+const BLKIO_WEIGHT_RANGE: (u16, u16) = (10, 1000);
+
+#[derive(Clone, Copy)]
+pub enum Throttle {
+    ReadBps,
+    WriteBps,
+    ReadIops,
+    WriteIops
+}
+
+// Limit on the rate of I/O to a block device
+pub struct DeviceLimit {
+    pub major: u64,
+    pub minor: u64,
+    pub throttle: Throttle,
+    pub rate: u64
+}
+
+// Limits applied to the container's cgroup, unset ones are left untouched
+pub struct Resources {
+    pub cpu_shares: Option<u64>,
+    pub cpu_quota: Option<i64>,
+    pub cpu_period: Option<u64>,
+    pub cpuset_cpus: Option<String>,
+    pub cpuset_mems: Option<String>,
+    pub memory_limit: Option<i64>,
+    pub memory_swap: Option<i64>,
+    // Soft limit, memory.soft_limit_in_bytes on v1 and memory.high on v2
+    pub memory_reservation: Option<i64>,
+    pub pids_limit: Option<i64>,
+    pub blkio_weight: Option<u16>,
+    pub device_limits: Vec<DeviceLimit>
+}
+
+impl Default for Resources {
+    fn default() -> Resources {
+        Resources {
+            cpu_shares: Some(CPU_SHARES),
+            cpu_quota: None,
+            cpu_period: None,
+            cpuset_cpus: None,
+            cpuset_mems: None,
+            memory_limit: Some(MEM_LIMIT),
+            memory_swap: None,
+            memory_reservation: None,
+            pids_limit: Some(MAX_PID),
+            blkio_weight: Some(BLKIO_WEIGHT),
+            device_limits: Vec::new()
+        }
+    }
+}
+
+impl Resources {
+    pub fn unlimited() -> Resources {
+        Resources {
+            cpu_shares: None, cpu_quota: None, cpu_period: None, cpuset_cpus: None, cpuset_mems: None,
+            memory_limit: None, memory_swap: None, memory_reservation: None, pids_limit: None, blkio_weight: None,
+            device_limits: Vec::new()
+        }
+    }
+
+    // Rucker's defaults, overridden by what is given on the command line
+    pub fn from_options(opt: &ResourceOptions) -> Result<Resources, ErrorType> {
+        let mut resources = Resources::default();
+        if let Some(memory) = &opt.memory {
+            resources.memory_limit = Some(parse_size(memory)? as i64);
+        }
+        if let Some(memory_swap) = &opt.memory_swap {
+            let memory_limit = opt.memory.as_ref().and(resources.memory_limit)
+                .ok_or_else(|| invalid_argument("--memory-swap requires --memory"))?;
+            let memory_swap = if memory_swap == "-1" { -1 } else { parse_size(memory_swap)? as i64 };
+            if memory_swap != -1 && memory_swap < memory_limit {
+                return Err(invalid_argument("--memory-swap, which includes the memory, can't be lower than --memory"));
+            }
+            resources.memory_swap = Some(memory_swap);
+        }
+        if let Some(reservation) = &opt.memory_reservation {
+            let reservation = parse_size(reservation)? as i64;
+            if opt.memory.is_some() && resources.memory_limit.is_some_and(|limit| reservation > limit) {
+                return Err(invalid_argument("--memory-reservation can't be higher than --memory"));
+            }
+            resources.memory_reservation = Some(reservation);
+        }
+        if let Some(cpus) = opt.cpus {
+            let available = std::thread::available_parallelism().map(|count| count.get() as f64).unwrap_or(f64::MAX);
+            if cpus <= 0.0 || cpus > available {
+                return Err(invalid_argument(&format!("--cpus must be between 0 and the {} available CPUs", available)));
+            }
+            resources.cpu_period = Some(CPU_PERIOD);
+            resources.cpu_quota = Some((cpus * CPU_PERIOD as f64) as i64);
+        }
+        if let Some(shares) = opt.cpu_shares {
+            if shares < CPU_SHARES_RANGE.0 || shares > CPU_SHARES_RANGE.1 {
+                return Err(invalid_argument(&format!("--cpu-shares must be between {} and {}", CPU_SHARES_RANGE.0, CPU_SHARES_RANGE.1)));
+            }
+            resources.cpu_shares = Some(shares);
+        }
+        if let Some(cpus) = &opt.cpuset_cpus { resources.cpuset_cpus = Some(validate_cpuset(cpus)?); }
+        if let Some(mems) = &opt.cpuset_mems { resources.cpuset_mems = Some(validate_cpuset(mems)?); }
+        if let Some(limit) = opt.pids_limit {
+            if limit == 0 { return Err(invalid_argument("--pids-limit must be positive, or negative for no limit")); }
+            resources.pids_limit = Some(limit);
+        }
+        if let Some(weight) = opt.blkio_weight {
+            if weight < BLKIO_WEIGHT_RANGE.0 || weight > BLKIO_WEIGHT_RANGE.1 {
+                return Err(invalid_argument(&format!("--blkio-weight must be between {} and {}", BLKIO_WEIGHT_RANGE.0, BLKIO_WEIGHT_RANGE.1)));
+            }
+            resources.blkio_weight = Some(weight);
+        }
+        let throttles = [
+            (&opt.device_read_bps, Throttle::ReadBps), (&opt.device_write_bps, Throttle::WriteBps),
+            (&opt.device_read_iops, Throttle::ReadIops), (&opt.device_write_iops, Throttle::WriteIops)
+        ];
+        for (limits, throttle) in throttles {
+            for limit in limits.iter() { resources.device_limits.push(parse_device_limit(limit, throttle)?); }
+        }
+        Ok(resources)
+    }
+}
+
+fn invalid_argument(message: &str) -> ErrorType {
+    ErrorType::InvalidArgumentError(message.to_string())
+}
+
+// A list of CPUs or memory nodes, like "0-3,6"
+fn validate_cpuset(cpuset: &str) -> Result<String, ErrorType> {
+    let is_valid = |range: &str| match range.split_once('-') {
+        Some((start, end)) => matches!((start.parse::<u32>(), end.parse::<u32>()), (Ok(start), Ok(end)) if start <= end),
+        None => range.parse::<u32>().is_ok()
+    };
+    if cpuset.split(',').all(is_valid) { Ok(cpuset.to_string()) } else { Err(invalid_argument(&format!("invalid cpuset {:?}, expected e.g. 0-3,6", cpuset))) }
+}
+
+// "<block device>:<rate>", the rate being a size for bps limits and a number for iops ones
+fn parse_device_limit(limit: &str, throttle: Throttle) -> Result<DeviceLimit, ErrorType> {
+    let (path, rate) = limit.rsplit_once(':')
+        .ok_or_else(|| invalid_argument(&format!("invalid device limit {:?}, expected <device>:<rate>", limit)))?;
+    let rate = match throttle {
+        Throttle::ReadBps | Throttle::WriteBps => parse_size(rate)?,
+        Throttle::ReadIops | Throttle::WriteIops => rate.parse().map_err(|_| invalid_argument(&format!("invalid rate {:?}", rate)))?
+    };
+    let device = stat(path).map_err(|err| invalid_argument(&format!("invalid device {}: {}", path, err.desc())))?;
+    if !SFlag::from_bits_truncate(device.st_mode).contains(SFlag::S_IFBLK) {
+        return Err(invalid_argument(&format!("{} is not a block device", path)));
+    }
+    Ok(DeviceLimit { major: major(device.st_rdev), minor: minor(device.st_rdev), throttle, rate })
+}
+
+fn build_v1_cgroup(name: &str, resources: &Resources) -> CgroupBuilder {
+    let mut cpu = CgroupBuilder::new(name).cpu();
+    if let Some(shares) = resources.cpu_shares { cpu = cpu.shares(shares); }
+    if let Some(quota) = resources.cpu_quota { cpu = cpu.quota(quota); }
+    if let Some(period) = resources.cpu_period { cpu = cpu.period(period); }
+    if let Some(cpus) = &resources.cpuset_cpus { cpu = cpu.cpus(cpus.clone()); }
+    if let Some(mems) = &resources.cpuset_mems { cpu = cpu.mems(mems.clone()); }
+    let mut memory = cpu.done().memory();
+    if let Some(limit) = resources.memory_reservation { memory = memory.memory_soft_limit(limit); }
+    if let Some(limit) = resources.memory_limit { memory = memory.memory_hard_limit(limit); }
+    if let Some(limit) = resources.memory_swap { memory = memory.memory_swap_limit(limit); }
+    let mut pid = memory.done().pid();
+    if let Some(limit) = resources.pids_limit {
+        pid = pid.maximum_number_of_processes(if limit < 0 { MaxValue::Max } else { MaxValue::Value(limit) });
+    }
+    let mut blkio = pid.done().blkio();
+    if let Some(weight) = resources.blkio_weight { blkio = blkio.weight(weight); }
+    for limit in resources.device_limits.iter() {
+        blkio = match limit.throttle {
+            Throttle::ReadBps => blkio.throttle_bps().read(limit.major, limit.minor, limit.rate),
+            Throttle::WriteBps => blkio.throttle_bps().write(limit.major, limit.minor, limit.rate),
+            Throttle::ReadIops => blkio.throttle_iops().read(limit.major, limit.minor, limit.rate),
+            Throttle::WriteIops => blkio.throttle_iops().write(limit.major, limit.minor, limit.rate)
+        };
+    }
+    blkio.done()
+}
 
 impl Container {
-    pub fn restrict_resources(&mut self) -> Result<(), ErrorType> {
-        let cgroup = CgroupBuilder::new(self.id.as_str())
-            .cpu().shares(256).done()
-            .memory().kernel_memory_limit(KMEM_LIMIT).memory_hard_limit(MEM_LIMIT).done()
-            .pid().maximum_number_of_processes(MAX_PID).done()
-            .blkio().weight(50).done()
-            .build(cgroups_rs::hierarchies::auto()).map_err(ErrorType::CgroupError)?;
-        log::debug!("Cgroup built successfully");
-        let pid : u64 = self.child_proc.as_ref().unwrap().pid.as_raw().try_into().unwrap();
-        cgroup.add_task(CgroupPid::from(pid)).map_err(ErrorType::CgroupError)?;
-        setrlimit(Resource::NOFILE, NOFILE_RLIMIT, NOFILE_RLIMIT).map_err(ErrorType::RlimitError)?;
+    // Before the clone, so that the child can be moved into the cgroup before it runs anything
+    pub fn create_cgroup(&mut self) -> Result<(), ErrorType> {
+        let cgroup = self.config.cgroup_path(&self.id);
+        if is_cgroup2_unified_mode() {
+            cgroup_v2::create_cgroup(&cgroup, &self.config.resources, self.cgroup_owner())?;
+        } else {
+            build_v1_cgroup(&relative_path(&cgroup).to_string_lossy(), &self.config.resources)
+                .build(Box::new(V1::new())).map_err(ErrorType::CgroupError)?;
+        }
+        log::debug!("Cgroup {} built successfully", cgroup.display());
         Ok(())
     }
-    pub fn clean_cgroup(&mut self) -> Result<(), ErrorType> {
-        let path = canonicalize(format!("/sys/fs/cgroup/{}/", self.id)).map_err(ErrorType::DirectoryError)?;
-        remove_dir(path).map_err(ErrorType::DirectoryError)?;
-        log::debug!("Cgroup cleaned");
+    // Moves the child into its cgroup, then lets it go on, see wait_for_cgroup
+    pub fn restrict_resources(&mut self) -> Result<(), ErrorType> {
+        join_cgroup(&self.config.cgroup_path(&self.id), self.child_proc.as_ref().unwrap().pid)?;
+        send_message(&self.parent_socket, &Message::CgroupJoined)
+    }
+    // First thing in the child: nothing runs outside of the container's cgroup. The cgroup namespace
+    // is unshared only then rather than created by clone, so that its root is the container's cgroup
+    pub fn wait_for_cgroup(&self) -> Result<(), ErrorType> {
+        set_recv_timeout(self.child_socket(), Some(HANDSHAKE_TIMEOUT))?;
+        match recv_message(self.child_socket())? {
+            Message::CgroupJoined => log::debug!("Child process joined its cgroup"),
+            message => return Err(unexpected_message(message))
+        }
+        if self.config.namespaces.contains(CloneFlags::CLONE_NEWCGROUP) {
+            unshare(CloneFlags::CLONE_NEWCGROUP).map_err(|err| ErrorType::NamespaceError(err, PathBuf::from("/proc/self/ns/cgroup")))?;
+        }
         Ok(())
     }
+    // With a user namespace, its root owns the container's cgroup on v2, to manage it from the inside
+    fn cgroup_owner(&self) -> Option<(u32, u32)> {
+        if !self.config.user_namespace { return None; }
+        let root = |mappings: &[IdMapping]| mappings.iter().find(|mapping| mapping.container_id == 0).map(|mapping| mapping.host_id);
+        Some((root(&self.config.uid_mappings)?, root(&self.config.gid_mappings)?))
+    }
+}
+
+// A cgroup path given by the user, like a --cgroup-parent, made relative to the root of the
+// hierarchies: "rucker.slice" and "/rucker.slice" are the same
+pub fn parse_cgroup_path(path: &str) -> Result<PathBuf, ErrorType> {
+    let path = Path::new("/").join(path);
+    if path.components().any(|component| matches!(component, Component::ParentDir | Component::CurDir)) {
+        return Err(invalid_argument(&format!("invalid cgroup path {}, it can't hold . or .. components", path.display())));
+    }
+    Ok(path)
+}
+
+// Path of the container's cgroup, relative to the root of the hierarchies
+pub fn cgroup_path(parent: &Path, id: &str) -> PathBuf {
+    parent.join(id)
+}
+
+pub fn relative_path(cgroup: &Path) -> &Path {
+    cgroup.strip_prefix("/").unwrap_or(cgroup)
+}
+
+// Directory of a cgroup in the hierarchy mounted at root
+pub fn hierarchy_path(root: &Path, cgroup: &Path) -> PathBuf {
+    root.join(relative_path(cgroup))
+}
+
+// Mount points of the v1 hierarchies, and of the unified one on hybrid setups
+fn v1_roots() -> Vec<PathBuf> {
+    let mut roots = Vec::new();
+    for line in read_to_string("/proc/self/mounts").unwrap_or_default().lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 3 || !matches!(fields[2], "cgroup" | "cgroup2") { continue; }
+        // cpu and cpuacct are often mounted together, and linked to from both names
+        if let Ok(root) = canonicalize(fields[1]) {
+            if !roots.contains(&root) { roots.push(root); }
+        }
+    }
+    roots
+}
+
+// Existing directories of a cgroup: its only one on v2, one per hierarchy on v1
+fn cgroup_dirs(cgroup: &Path) -> Vec<PathBuf> {
+    let roots = if is_cgroup2_unified_mode() { vec![PathBuf::from(CGROUP_ROOT)] } else { v1_roots() };
+    roots.iter().map(|root| hierarchy_path(root, cgroup)).filter(|dir| dir.is_dir()).collect()
+}
+
+// Subdirectories first, so that they can be removed in order
+fn subtree(dir: &Path) -> Result<Vec<PathBuf>, ErrorType> {
+    let mut dirs = Vec::new();
+    for entry in read_dir(dir).map_err(|err| ErrorType::CgroupFileError(err, dir.to_path_buf()))? {
+        let path = entry.map_err(|err| ErrorType::CgroupFileError(err, dir.to_path_buf()))?.path();
+        if path.is_dir() { dirs.extend(subtree(&path)?); }
+    }
+    dirs.push(dir.to_path_buf());
+    Ok(dirs)
+}
+
+// Moves a process, e.g. one started by `rucker exec`, into the cgroup of a container
+pub fn join_cgroup(cgroup: &Path, pid: Pid) -> Result<(), ErrorType> {
+    for dir in cgroup_dirs(cgroup) {
+        let path = dir.join("cgroup.procs");
+        write(&path, pid.to_string()).map_err(|err| ErrorType::CgroupFileError(err, path))?;
+    }
+    Ok(())
+}
+
+// Signals every process in the cgroup of a container and below, not only its init: processes
+// that left its pid namespace, e.g. through `rucker exec`, would otherwise outlive it
+pub fn signal_cgroup(cgroup: &Path, signal: Signal) -> Result<(), ErrorType> {
+    if signal == Signal::SIGKILL && is_cgroup2_unified_mode() && cgroup_v2::kill_cgroup(cgroup).is_ok() {
+        log::debug!("Killed cgroup {} through cgroup.kill", cgroup.display());
+        return Ok(());
+    }
+    for dir in cgroup_dirs(cgroup) {
+        for dir in subtree(&dir)? {
+            // Gone once its processes exited, if the container removed it itself
+            let Ok(procs) = read_to_string(dir.join("cgroup.procs")) else { continue; };
+            for pid in procs.lines().filter_map(|pid| pid.parse().ok()) {
+                match kill(Pid::from_raw(pid), signal) {
+                    Ok(()) | Err(Errno::ESRCH) => (),
+                    Err(err) => return Err(ErrorType::SignalError(err))
+                }
+            }
+        }
+    }
+    log::debug!("Sent {} to every process in cgroup {}", signal, cgroup.display());
+    Ok(())
+}
+
+// Removes the cgroup of a container from every hierarchy, along with the cgroups the container
+// made below it. Killed processes take a moment to leave, so removal is retried with a backoff
+pub fn clean_cgroup(cgroup: &Path) -> Result<(), ErrorType> {
+    for dir in cgroup_dirs(cgroup) {
+        for dir in subtree(&dir)? {
+            let mut delay = Duration::from_millis(10);
+            loop {
+                match remove_dir(&dir) {
+                    Ok(()) => break,
+                    Err(err) if err.kind() == ErrorKind::NotFound => break,
+                    Err(err) if err.raw_os_error() == Some(libc::EBUSY) && delay < Duration::from_secs(1) => {
+                        sleep(delay);
+                        delay *= 2;
+                    },
+                    Err(err) => return Err(ErrorType::CgroupFileError(err, dir))
+                }
+            }
+        }
+    }
+    log::debug!("Cgroup {} cleaned", cgroup.display());
+    Ok(())
 }
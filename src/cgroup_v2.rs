@@ -0,0 +1,136 @@
+use crate::errors::ErrorType;
+use crate::cgroup::{Resources, Throttle, CPU_PERIOD, hierarchy_path};
+
+use nix::unistd::{chown, Gid, Uid};
+
+use std::fs::{create_dir, read_to_string, write};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+pub const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+// Controllers rucker sets limits through, enabled from the root down to the container's cgroup
+const CONTROLLERS: [&str; 5] = ["cpu", "cpuset", "io", "memory", "pids"];
+// Files the owner of a delegated cgroup needs to manage its subtree, see cgroups(7)
+const DELEGATED_FILES: [&str; 3] = ["cgroup.procs", "cgroup.threads", "cgroup.subtree_control"];
+
+fn read_file(dir: &Path, file: &str) -> Result<String, ErrorType> {
+    let path = dir.join(file);
+    read_to_string(&path).map_err(|err| ErrorType::CgroupFileError(err, path))
+}
+
+fn write_file(dir: &Path, file: &str, content: &str) -> Result<(), ErrorType> {
+    let path = dir.join(file);
+    write(&path, content).map_err(|err| ErrorType::CgroupFileError(err, path))
+}
+
+fn controllers(dir: &Path, file: &str) -> Result<Vec<String>, ErrorType> {
+    Ok(read_file(dir, file)?.split_whitespace().map(String::from).collect())
+}
+
+// Creates the missing ancestors of the cgroup and the cgroup itself, enabling the controllers in
+// every one above it. Returns the controllers available in the cgroup.
+// A cgroup with processes of its own can't hand domain controllers down to its children (the
+// "no internal processes" rule), the root excepted: such a parent is reported, as moving its
+// processes out of it is not rucker's call
+fn create_hierarchy(cgroup: &Path) -> Result<Vec<String>, ErrorType> {
+    let mut dir = PathBuf::from(CGROUP_ROOT);
+    for component in cgroup.strip_prefix("/").unwrap_or(cgroup).components() {
+        let enabled = controllers(&dir, "cgroup.subtree_control")?;
+        let missing: Vec<String> = controllers(&dir, "cgroup.controllers")?.into_iter()
+            .filter(|controller| CONTROLLERS.contains(&controller.as_str()) && !enabled.contains(controller))
+            .map(|controller| format!("+{}", controller))
+            .collect();
+        if !missing.is_empty() {
+            let path = dir.join("cgroup.subtree_control");
+            match write(&path, missing.join(" ")) {
+                Ok(()) => log::debug!("Enabled {} in {}", missing.join(" "), dir.display()),
+                Err(err) if err.raw_os_error() == Some(libc::EBUSY) => return Err(ErrorType::InvalidStateError(format!(
+                    "cgroup {} has processes of its own, so it can't delegate controllers to containers, use another --cgroup-parent",
+                    dir.display()))),
+                Err(err) => return Err(ErrorType::CgroupFileError(err, path))
+            }
+        }
+        dir.push(component);
+        match create_dir(&dir) {
+            Err(err) if err.kind() != ErrorKind::AlreadyExists => return Err(ErrorType::CgroupFileError(err, dir)),
+            _ => ()
+        }
+    }
+    controllers(&dir, "cgroup.controllers")
+}
+
+// cpu.weight ranges from 1 to 10000 where shares range from 2 to 262144, as converted by runc
+fn cpu_weight(shares: u64) -> u64 {
+    1 + (shares.max(2) - 2) * 9999 / 262142
+}
+
+// io.weight ranges from 1 to 10000 where the blkio weight ranges from 10 to 1000
+fn io_weight(weight: u16) -> u64 {
+    1 + (weight.max(10) as u64 - 10) * 9999 / 990
+}
+
+fn max_or(value: i64) -> String {
+    if value < 0 { "max".to_string() } else { value.to_string() }
+}
+
+// The limits as the cgroup v2 files they are written to
+fn limits(resources: &Resources) -> Vec<(&'static str, String)> {
+    let mut limits = Vec::new();
+    if let Some(cpus) = &resources.cpuset_cpus { limits.push(("cpuset.cpus", cpus.clone())); }
+    if let Some(mems) = &resources.cpuset_mems { limits.push(("cpuset.mems", mems.clone())); }
+    if let Some(shares) = resources.cpu_shares { limits.push(("cpu.weight", cpu_weight(shares).to_string())); }
+    if resources.cpu_quota.is_some() || resources.cpu_period.is_some() {
+        let quota = resources.cpu_quota.filter(|&quota| quota > 0).map_or("max".to_string(), |quota| quota.to_string());
+        limits.push(("cpu.max", format!("{} {}", quota, resources.cpu_period.unwrap_or(CPU_PERIOD))));
+    }
+    if let Some(reservation) = resources.memory_reservation { limits.push(("memory.high", max_or(reservation))); }
+    if let Some(limit) = resources.memory_limit { limits.push(("memory.max", max_or(limit))); }
+    // Unlike memory.memsw.limit_in_bytes on v1, memory.swap.max doesn't count the memory
+    match (resources.memory_swap, resources.memory_limit) {
+        (Some(-1), _) => limits.push(("memory.swap.max", "max".to_string())),
+        (Some(swap), Some(limit)) if limit >= 0 => limits.push(("memory.swap.max", (swap - limit).max(0).to_string())),
+        (Some(_), _) => log::warn!("A swap limit is only applied along with a memory limit on cgroup v2"),
+        (None, _) => ()
+    }
+    if let Some(limit) = resources.pids_limit { limits.push(("pids.max", max_or(limit))); }
+    if let Some(weight) = resources.blkio_weight { limits.push(("io.weight", format!("default {}", io_weight(weight)))); }
+    for limit in resources.device_limits.iter() {
+        let key = match limit.throttle {
+            Throttle::ReadBps => "rbps",
+            Throttle::WriteBps => "wbps",
+            Throttle::ReadIops => "riops",
+            Throttle::WriteIops => "wiops"
+        };
+        limits.push(("io.max", format!("{}:{} {}={}", limit.major, limit.minor, key, limit.rate)));
+    }
+    limits
+}
+
+// Builds the container's cgroup in the unified hierarchy. Given an owner, typically the root of the
+// container's user namespace, the cgroup is delegated to it so that it can manage its own subtree
+pub fn create_cgroup(cgroup: &Path, resources: &Resources, owner: Option<(u32, u32)>) -> Result<(), ErrorType> {
+    let available = create_hierarchy(cgroup)?;
+    let dir = hierarchy_path(Path::new(CGROUP_ROOT), cgroup);
+    for (file, value) in limits(resources) {
+        let controller = file.split('.').next().unwrap_or(file);
+        if !available.iter().any(|available| available == controller) {
+            log::warn!("The {} controller is not available, {} is left unset", controller, file);
+            continue;
+        }
+        write_file(&dir, file, &value)?;
+    }
+    if let Some((uid, gid)) = owner {
+        let paths = std::iter::once(dir.clone()).chain(DELEGATED_FILES.iter().map(|file| dir.join(file)));
+        for path in paths {
+            chown(&path, Some(Uid::from_raw(uid)), Some(Gid::from_raw(gid)))
+                .map_err(|err| ErrorType::CgroupFileError(err.into(), path))?;
+        }
+        log::debug!("Delegated cgroup {} to {}:{}", dir.display(), uid, gid);
+    }
+    Ok(())
+}
+
+// cgroup.kill, since Linux 5.14, kills the whole subtree without racing with processes forking
+pub fn kill_cgroup(cgroup: &Path) -> Result<(), ErrorType> {
+    write_file(&hierarchy_path(Path::new(CGROUP_ROOT), cgroup), "cgroup.kill", "1")
+}
@@ -1,15 +1,51 @@
-use crate::errors::ErrorType;
+use crate::errors::{ErrorType, SetupFailure, EXIT_SIGNAL_OFFSET};
 use crate::container::Container;
-use crate::internal::{set_hostname, set_mountpoint, drop_capabilities, restrict_syscalls};
+use crate::ipc::{Message, send_message};
+use crate::tty::{attach_terminal, redirect_stdio};
+use crate::logs::{open_log_pipes, redirect_output};
+use crate::rlimits::set_rlimits;
+use crate::network::bring_up_loopback;
+use crate::etc_files::etc_mounts;
+use crate::namespace::switch_user;
+use crate::init::init;
+use crate::internal::{join_namespace, set_hostname, set_mountpoint, set_workdir, resolve_command, wait_for_start, drop_capabilities, restrict_syscalls};
 
 use nix::unistd::{Pid, execve};
 use nix::sched::{clone, CloneFlags};
-use nix::sys::{signal::Signal, wait::waitpid};
+use nix::sys::{signal::{kill, SigSet, Signal}, signalfd::SignalFd, wait::{waitpid, WaitPidFlag, WaitStatus}};
 
 use std::ffi::CString;
+use std::os::fd::OwnedFd;
 
 const STACK_SIZE: usize = 1024 * 1024;
 
+// Signals rucker passes on to the container process rather than dying from them
+const FORWARDED_SIGNALS: [Signal; 6] = [
+    Signal::SIGINT, Signal::SIGTERM, Signal::SIGHUP, Signal::SIGWINCH, Signal::SIGUSR1, Signal::SIGUSR2
+];
+
+// To be blocked while waiting with ChildProcess::wait_forwarding
+pub fn forwarded_signals() -> SigSet {
+    let mut signals = SigSet::empty();
+    for signal in FORWARDED_SIGNALS.iter() { signals.add(*signal); }
+    signals.add(Signal::SIGCHLD);
+    signals
+}
+
+fn exit_code(status: WaitStatus) -> Option<i32> {
+    match status {
+        WaitStatus::Exited(_, code) => {
+            log::debug!("Child process exited with status {}", code);
+            Some(code)
+        },
+        WaitStatus::Signaled(_, signal, _) => {
+            log::debug!("Child process was killed by {:?}", signal);
+            Some(EXIT_SIGNAL_OFFSET + signal as i32)
+        },
+        _ => None
+    }
+}
+
 pub struct ChildProcess {
     pub pid: Pid
 }
@@ -18,35 +54,89 @@ impl ChildProcess {
     pub fn of(pid: Pid) -> ChildProcess {
         ChildProcess { pid }
     }
-    pub fn wait(&mut self) -> Result<(), ErrorType> {
+    // Fields of /proc/<pid>/stat following the command name, which is parenthesized
+    // and may contain spaces: the first one is the state (field 3 in proc(5))
+    fn stat_fields(&self) -> Option<Vec<String>> {
+        let stat = std::fs::read_to_string(format!("/proc/{}/stat", self.pid)).ok()?;
+        let (_, rest) = stat.rsplit_once(')')?;
+        Some(rest.split_whitespace().map(String::from).collect())
+    }
+    // A zombie is as good as dead: only its parent can still reap it
+    pub fn is_alive(&self) -> bool {
+        if kill(self.pid, None).is_err() { return false; }
+        self.stat_fields().is_some_and(|fields| fields.first().map(String::as_str) != Some("Z"))
+    }
+    // In clock ticks since boot, tells a process apart from a later one reusing its pid
+    pub fn start_time(&self) -> Option<u64> {
+        self.stat_fields()?.get(19)?.parse().ok()
+    }
+    pub fn kill(&self, signal: Signal) -> Result<(), ErrorType> {
+        kill(self.pid, signal).map_err(ErrorType::SignalError)
+    }
+    pub fn wait(&mut self) -> Result<i32, ErrorType> {
         log::debug!("Waiting for child_proc (pid {}) to finish", self.pid);
-        waitpid(self.pid, None).map_err(ErrorType::WaitingError)?;
-        Ok(())
+        loop {
+            if let Some(code) = exit_code(waitpid(self.pid, None).map_err(ErrorType::WaitingError)?) { return Ok(code); }
+        }
+    }
+    // For the wait loops polling a signalfd of forwarded_signals(): reaps the child on
+    // SIGCHLD, returning its exit status if it exited, and forwards any other signal
+    pub fn handle_signal(&mut self, signal_fd: &SignalFd) -> Result<Option<i32>, ErrorType> {
+        let signal = match signal_fd.read_signal().map_err(ErrorType::SignalError)? {
+            Some(info) => Signal::try_from(info.ssi_signo as i32).map_err(ErrorType::SignalError)?,
+            None => return Ok(None)
+        };
+        if signal == Signal::SIGCHLD {
+            return Ok(exit_code(waitpid(self.pid, Some(WaitPidFlag::WNOHANG)).map_err(ErrorType::WaitingError)?));
+        }
+        log::debug!("Forwarding {} to the child process", signal);
+        if let Err(err) = self.kill(signal) { log::debug!("Failed to forward {}: {:?}", signal, err); }
+        Ok(None)
+    }
+    // Like wait, but forwards the signals rucker receives meanwhile to the child,
+    // which requires forwarded_signals() to be blocked
+    pub fn wait_forwarding(&mut self) -> Result<i32, ErrorType> {
+        log::debug!("Waiting for child_proc (pid {}) to finish, forwarding signals", self.pid);
+        let signals = forwarded_signals();
+        loop {
+            // A SIGCHLD for an exit happening after this check stays pending for the sigwait below
+            let status = waitpid(self.pid, Some(WaitPidFlag::WNOHANG)).map_err(ErrorType::WaitingError)?;
+            if let Some(code) = exit_code(status) { return Ok(code); }
+            match signals.wait().map_err(ErrorType::SignalError)? {
+                Signal::SIGCHLD => continue,
+                signal => {
+                    log::debug!("Forwarding {} to the child process", signal);
+                    if let Err(err) = self.kill(signal) { log::debug!("Failed to forward {}: {:?}", signal, err); }
+                }
+            }
+        }
     }
 }
 
-fn handle_internal(result: Result<isize, ErrorType>) -> isize {
+fn handle_internal(result: Result<isize, ErrorType>, socket: Option<&OwnedFd>) -> isize {
     match result {
         Ok(pid) => pid,
         Err(err_type) => {
+            let exit_code = err_type.exit_code() as isize;
+            let failure = SetupFailure::from(&err_type);
             if let ErrorType::HostnameError(err) = err_type {
                 log::error!("Failed to set container hostname: {:?}", err);
             } else if let ErrorType::DirectoryError(err) = err_type {
                 log::error!("DirectoryError: {:?}", err);
-            } else if let ErrorType::ChDirError(err) = err_type {
-                log::error!("ChDirError: {:?}", err);
-            } else if let ErrorType::MountError(err) = err_type {
-                log::error!("MountError: {:?}", err);
-            } else if let ErrorType::UnmountError(err) = err_type {
-                log::error!("UnmountError: {:?}", err);
-            } else if let ErrorType::PivotRootError(err) = err_type {
-                log::error!("Failed to pivot root: {:?}", err);
-            } else if let ErrorType::SocketCloseError(err) = err_type {
-                log::error!("Failed to close socket fd: {:?}", err);
+            } else if let ErrorType::ChDirError(err, path) = err_type {
+                log::error!("ChDirError: {:?} ({})", err, path.display());
+            } else if let ErrorType::MountError(err, path) = err_type {
+                log::error!("MountError: {:?} ({})", err, path.display());
+            } else if let ErrorType::UnmountError(err, path) = err_type {
+                log::error!("UnmountError: {:?} ({})", err, path.display());
+            } else if let ErrorType::PivotRootError(err, path) = err_type {
+                log::error!("Failed to pivot root: {:?} ({})", err, path.display());
             } else if let ErrorType::SocketSendError(err) = err_type {
                 log::error!("Failed to send via socket: {:?}", err);
             } else if let ErrorType::SocketRecvError(err) = err_type {
                 log::error!("Failed to recv via socket: {:?}", err);
+            } else if let ErrorType::IpcProtocolError(err) = err_type {
+                log::error!("Failed to communicate with parent process: {}", err);
             } else if let ErrorType::ExecveError(err) = err_type {
                 log::error!("Failed to perform execve: {:?}", err);
             } else if let ErrorType::UserSysError(err) = err_type {
@@ -55,35 +145,86 @@ fn handle_internal(result: Result<isize, ErrorType>) -> isize {
                 log::error!("Failed to restrict capabilities of child process: {:?}", err);
             } else if let ErrorType::SyscallError(err) = err_type {
                 log::error!("Failed to restrict syscalls for child process: {:?}", err);
+            } else if let ErrorType::CommandNotFoundError(command) = err_type {
+                log::error!("Command not found in the container: {}", command);
+            } else if let ErrorType::CommandNotExecutableError(command) = err_type {
+                log::error!("Command is not executable in the container: {}", command);
+            } else if let ErrorType::CStringError(err) = err_type {
+                log::error!("Error converting path to CString: {:?}", err);
+            } else if let ErrorType::NamespaceError(err, path) = err_type {
+                log::error!("Failed to join namespace {}: {:?}", path.display(), err);
+            } else if let ErrorType::ExecFifoError(err) = err_type {
+                log::error!("Failed to wait for the container to be started: {:?}", err);
+            } else if let ErrorType::TtyError(err) = err_type {
+                log::error!("Failed to set up the terminal of the container: {:?}", err);
+            } else if let ErrorType::LogError(err) = err_type {
+                log::error!("Failed to set up the logging of the container: {:?}", err);
+            } else if let ErrorType::NetlinkError(err, request) = err_type {
+                log::error!("Failed to set up the network of the container, could not {}: {:?}", request, err);
+            } else if let ErrorType::RlimitError(err) = err_type {
+                log::error!("Failed to set the resource limits of the container: {:?}", err);
+            }
+            // Closed once the init runs, the parent no longer waiting for the setup by then
+            if let Some(Err(err)) = socket.map(|socket| send_message(socket, &Message::SetupFailure(failure))) {
+                log::error!("Failed to report setup failure to the parent process: {:?}", err);
             }
-            -1
+            exit_code
         }
     }
 }
 
 impl Container {
     fn child_process(&mut self) -> Result<isize, ErrorType> {
-        set_hostname(&self.id)?;
-        set_mountpoint(&self.mount_dir, &self.addmntpts)?;
+        // Signals are only blocked in rucker itself, see Container::launch
+        if let Some(mask) = self.signal_mask { mask.thread_set_mask().map_err(ErrorType::SignalError)?; }
+        self.wait_for_cgroup()?;
+        let config = &self.config;
+        for (flag, path) in config.join_namespaces.iter() { join_namespace(*flag, path)?; }
+        if config.loopback && config.namespaces.contains(CloneFlags::CLONE_NEWNET) { bring_up_loopback()?; }
+        if config.namespaces.contains(CloneFlags::CLONE_NEWUTS) {
+            set_hostname(config.hostname.as_ref().unwrap_or(&self.id))?;
+        }
+        let etc_mounts = if config.etc_files.is_some() { etc_mounts(&self.id) } else { Vec::new() };
+        set_mountpoint(&config.mount_dir, &etc_mounts, &config.mounts, config.readonly_rootfs)?;
+        if let Some(slave) = &self.tty_slave { attach_terminal(slave)?; }
+        set_workdir(&config.workdir)?;
+        set_rlimits(&config.rlimits)?;
         self.setup_user_namespace()?;
-        drop_capabilities()?;
-        restrict_syscalls()?;
-        log::info!("Starting container with <exec_command:{}>", self.exec_command.to_str().unwrap());
-        execve::<CString, CString>(&self.exec_command, &[], &[]).map_err(ErrorType::ExecveError)?;
+        let config = &self.config;
+        // Dropping from the bounding set takes CAP_SETPCAP, which a non-root user no longer has
+        drop_capabilities(&config.capabilities_drop)?;
+        switch_user(config.uid, config.gid)?;
+        if let Some(profile) = &config.seccomp { restrict_syscalls(profile)?; }
+        let command = resolve_command(&config.argv[0], &config.envp)?;
+        send_message(self.child_socket(), &Message::Ready)?;
+        if let Some(exec_fifo) = &self.exec_fifo { wait_for_start(exec_fifo)?; }
+        log::info!("Starting container with <command:{:?}>", config.argv);
+        if let Some(slave) = &self.tty_slave { redirect_stdio(slave)?; }
+        if let Some([_, stdout, _, stderr]) = &self.log_pipes { redirect_output(stdout, stderr)?; }
+        if config.init {
+            // The init stays in this process instead of calling execve, which would have closed
+            // them: the parent takes the child socket closing for the container having started
+            self.child_socket = None;
+            self.exec_fifo = None;
+            self.tty_master = None;
+            self.tty_slave = None;
+            self.log_pipes = None;
+            return init(&command, &self.config.argv, &self.config.envp).map(|exit_code| exit_code as isize);
+        }
+        execve::<CString, CString>(&command, &config.argv, &config.envp).map_err(ErrorType::ExecveError)?;
         Ok(0)
     }
 
     pub fn create_child_process(&mut self) -> Result<ChildProcess, ErrorType> {
         let mut tmp_stack: [u8; STACK_SIZE] = [0; STACK_SIZE];
-        let mut flags = CloneFlags::empty();
-        flags.insert(CloneFlags::CLONE_NEWNS);
-        flags.insert(CloneFlags::CLONE_NEWCGROUP);
-        flags.insert(CloneFlags::CLONE_NEWPID);
-        flags.insert(CloneFlags::CLONE_NEWIPC);
-        flags.insert(CloneFlags::CLONE_NEWNET);
-        flags.insert(CloneFlags::CLONE_NEWUTS);
+        // The cgroup namespace is unshared by the child once in its cgroup, see wait_for_cgroup
+        let flags = self.config.namespaces - CloneFlags::CLONE_NEWCGROUP;
+        if self.config.log.is_some() { self.log_pipes = Some(open_log_pipes()?); }
         match unsafe { clone(
-            Box::new(|| handle_internal(self.child_process())),
+            Box::new(|| {
+                let result = self.child_process();
+                handle_internal(result, self.child_socket.as_ref())
+            }),
             &mut tmp_stack,
             flags,
             Some(Signal::SIGCHLD as i32)
@@ -0,0 +1,122 @@
+use crate::capabilities::CAPABILITIES_DROP;
+use crate::cgroup::{Resources, cgroup_path};
+use crate::etc_files::EtcFiles;
+use crate::logs::LogConfig;
+use crate::network::NetworkConfig;
+use crate::rlimits::{Rlimit, DEFAULT_RLIMITS};
+use crate::syscalls::SeccompProfile;
+
+use capctl::caps::Cap;
+use nix::mount::MsFlags;
+use nix::sched::CloneFlags;
+
+use std::ffi::CString;
+use std::path::PathBuf;
+
+const USERNS_OFFSET: u32 = 10000;
+const USERNS_COUNT: u32 = 2000;
+pub const DEFAULT_CGROUP_PARENT: &str = "/rucker";
+
+pub struct Mount {
+    pub source: Option<PathBuf>,
+    // Relative to the root of the container
+    pub destination: PathBuf,
+    pub fstype: Option<String>,
+    pub flags: MsFlags,
+    pub data: Option<String>
+}
+
+impl Mount {
+    pub fn bind(source: PathBuf, destination: PathBuf) -> Mount {
+        Mount { source: Some(source), destination, fstype: None, flags: MsFlags::MS_BIND | MsFlags::MS_PRIVATE, data: None }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct IdMapping {
+    pub container_id: u32,
+    pub host_id: u32,
+    pub size: u32
+}
+
+// Everything needed to set up a container, whether it comes from the
+// `run` command line or from an OCI bundle
+pub struct ContainerConfig {
+    pub argv: Vec<CString>,
+    pub envp: Vec<CString>,
+    pub mount_dir: PathBuf,
+    pub readonly_rootfs: bool,
+    pub mounts: Vec<Mount>,
+    pub workdir: PathBuf,
+    pub uid: u32,
+    pub gid: u32,
+    // Runs the command under rucker's own init, see init.rs
+    pub init: bool,
+    // Gives the command a pty, with rucker's stdin forwarded to it if interactive
+    pub tty: bool,
+    pub interactive: bool,
+    // Captures the output of the command to its log file instead of sharing rucker's
+    pub log: Option<LogConfig>,
+    // Defaults to the container ID
+    pub hostname: Option<String>,
+    // Generates /etc/hosts, /etc/hostname and /etc/resolv.conf instead of using the rootfs's
+    pub etc_files: Option<EtcFiles>,
+    // Namespaces created by clone, the user namespace being unshared by the child itself
+    pub namespaces: CloneFlags,
+    // Brings up the loopback interface of a new network namespace
+    pub loopback: bool,
+    // Connects the network namespace to a bridge
+    pub network: Option<NetworkConfig>,
+    pub join_namespaces: Vec<(CloneFlags, PathBuf)>,
+    pub user_namespace: bool,
+    pub uid_mappings: Vec<IdMapping>,
+    pub gid_mappings: Vec<IdMapping>,
+    pub resources: Resources,
+    // Set in the child before execve
+    pub rlimits: Vec<Rlimit>,
+    // Relative to the root of the cgroup hierarchies, the container's cgroup being
+    // <cgroup_parent>/<id> unless cgroups_path, e.g. from an OCI bundle, says otherwise
+    pub cgroup_parent: PathBuf,
+    pub cgroups_path: Option<PathBuf>,
+    pub capabilities_drop: Vec<Cap>,
+    // None leaves the container unconfined
+    pub seccomp: Option<SeccompProfile>
+}
+
+impl ContainerConfig {
+    pub fn new(argv: Vec<CString>, envp: Vec<CString>, mount_dir: PathBuf) -> ContainerConfig {
+        let id_mapping = IdMapping { container_id: 0, host_id: USERNS_OFFSET, size: USERNS_COUNT };
+        ContainerConfig {
+            argv, envp, mount_dir,
+            readonly_rootfs: false,
+            mounts: Vec::new(),
+            workdir: PathBuf::from("/"),
+            uid: 0,
+            gid: 0,
+            init: false,
+            tty: false,
+            interactive: false,
+            log: None,
+            hostname: None,
+            etc_files: None,
+            namespaces: CloneFlags::CLONE_NEWNS | CloneFlags::CLONE_NEWCGROUP | CloneFlags::CLONE_NEWPID
+                | CloneFlags::CLONE_NEWIPC | CloneFlags::CLONE_NEWNET | CloneFlags::CLONE_NEWUTS,
+            loopback: true,
+            network: None,
+            join_namespaces: Vec::new(),
+            user_namespace: true,
+            uid_mappings: vec![id_mapping],
+            gid_mappings: vec![id_mapping],
+            resources: Resources::default(),
+            rlimits: DEFAULT_RLIMITS.to_vec(),
+            cgroup_parent: PathBuf::from(DEFAULT_CGROUP_PARENT),
+            cgroups_path: None,
+            capabilities_drop: CAPABILITIES_DROP.to_vec(),
+            seccomp: Some(SeccompProfile::default())
+        }
+    }
+
+    pub fn cgroup_path(&self, id: &str) -> PathBuf {
+        self.cgroups_path.clone().unwrap_or_else(|| cgroup_path(&self.cgroup_parent, id))
+    }
+}
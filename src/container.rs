@@ -1,69 +1,276 @@
 use crate::errors::ErrorType;
 use crate::utils::random_hex_string;
-use crate::ipc::{create_socketpair, send_boolean, recv_boolean};
-use crate::childproc::ChildProcess;
+use crate::ipc::{Message, create_socketpair, send_message, recv_message, recv_setup_result, set_recv_timeout, unexpected_message, HANDSHAKE_TIMEOUT};
+use crate::childproc::{ChildProcess, forwarded_signals};
+use crate::environ::build_environment;
+use crate::config::{ContainerConfig, Mount};
+use crate::state::{ContainerState, log_path, now};
+use crate::logs::LogConfig;
+use crate::cgroup::{Resources, clean_cgroup, parse_cgroup_path, signal_cgroup};
+use crate::tty::open_pty;
+use crate::rlimits::build_rlimits;
+use crate::ports::build_ports;
+use crate::oom::{is_oom_killed, oom_kills, watch_oom};
+use crate::etc_files::{EtcFiles, validate_hostname, write_etc_files, write_hosts};
+use crate::network::{NetworkConfig, NetworkMode, parse_network_mode, setup_network, teardown_network};
+use crate::errors::SetupFailure;
+use crate::RunOptions;
 
-use nix::unistd::close;
+use nix::fcntl::{open, OFlag};
+use nix::sched::CloneFlags;
+use nix::sys::signal::{SigSet, SigmaskHow, Signal};
+use nix::sys::stat::Mode;
+use nix::unistd::{dup2, fork, setsid, ForkResult};
 
 use std::ffi::CString;
-use std::os::fd::{AsRawFd, OwnedFd};
+use std::net::Ipv4Addr;
+use std::os::fd::OwnedFd;
 use std::path::PathBuf;
 
 pub struct Container {
     pub id: String,
-    pub exec_command: CString,
-    pub mount_dir: PathBuf,
-    pub addmntpts: Vec<(PathBuf, PathBuf)>,
+    pub config: ContainerConfig,
     pub child_proc: Option<ChildProcess>,
-    pub socket_pair: (OwnedFd, OwnedFd),
-    pub uid: u32
+    // The parent's and the child's ends of the socketpair, the latter being
+    // closed in the parent right after clone
+    pub parent_socket: OwnedFd,
+    pub child_socket: Option<OwnedFd>,
+    // Read end of the fifo the child waits on before execve, for `rucker create`
+    pub exec_fifo: Option<OwnedFd>,
+    // Recorded in the container state, for `rucker create`
+    pub bundle: Option<PathBuf>,
+    // Run by a supervisor in the background, see run_detached
+    pub detached: bool,
+    // The signal mask before launch blocked the forwarded signals, restored in the child
+    pub signal_mask: Option<SigSet>,
+    // Ends of the pty when config.tty is set, the slave being closed in the parent right after clone
+    pub tty_master: Option<OwnedFd>,
+    pub tty_slave: Option<OwnedFd>,
+    // Pipes the output of the command is captured through when config.log is set, see
+    // open_log_pipes. Only their read ends are kept in the parent, as log_output, after clone
+    pub log_pipes: Option<[OwnedFd; 4]>,
+    pub log_output: Option<[OwnedFd; 2]>,
+    // Given to the container by setup_network
+    pub ip: Option<Ipv4Addr>
 }
 
 impl Container {
-    pub fn new(exec_command: CString, mount_dir: PathBuf, addmntpts: Vec<(PathBuf, PathBuf)>, uid: u32) -> Result<Container, ErrorType> {
-        let id = random_hex_string();
+    pub fn new(id: String, config: ContainerConfig) -> Result<Container, ErrorType> {
         log::info!("Successfully newed a container, ID: {}", id);
-        Ok(Container { id, exec_command, mount_dir, addmntpts, socket_pair: create_socketpair()?, child_proc: None, uid })
+        let (parent_socket, child_socket) = create_socketpair()?;
+        Ok(Container { id, config, parent_socket, child_socket: Some(child_socket), child_proc: None, exec_fifo: None, bundle: None, detached: false, signal_mask: None, tty_master: None, tty_slave: None, log_pipes: None, log_output: None, ip: None })
     }
+    pub fn child_socket(&self) -> &OwnedFd {
+        self.child_socket.as_ref().expect("PanickedException: The child socket is only available in the child process")
+    }
+    // Returns once the child is set up and about to execve
     pub fn create(&mut self) -> Result<(), ErrorType> {
+        self.create_cgroup()?;
+        if let Some(files) = &self.config.etc_files {
+            let host_network = !self.config.namespaces.contains(CloneFlags::CLONE_NEWNET);
+            write_etc_files(&self.id, self.hostname(), files, host_network)?;
+        }
         let child_process = self.create_child_process()?;
         log::info!("Successfully created child process: {:?}", child_process.pid);
         self.child_proc = Some(child_process);
+        self.child_socket = None;
+        self.exec_fifo = None;
+        self.tty_slave = None;
+        self.log_output = self.log_pipes.take().map(|[stdout, _, stderr, _]| [stdout, stderr]);
         self.restrict_resources()?;
         log::info!("Successfully restricted resources");
-        if recv_boolean(&self.socket_pair.0)? {
-            self.map_child_uid()?;
-            send_boolean(&self.socket_pair.0, true)?;
-            log::info!("Successfully mapped UID/GID for child process");
-        } else {
-            log::debug!("Namespace issue, skipped id mapping");
-            send_boolean(&self.socket_pair.0, false)?;
+        // Done before the id mapping, which the child waits for before it can execve
+        if let Some(network) = &self.config.network {
+            self.ip = Some(setup_network(&self.id, self.child_proc.as_ref().unwrap().pid, network)?);
+            if let Some(files) = &self.config.etc_files { write_hosts(&self.id, self.hostname(), self.ip, files)?; }
+        }
+        set_recv_timeout(&self.parent_socket, Some(HANDSHAKE_TIMEOUT))?;
+        match recv_message(&self.parent_socket)? {
+            Message::UserNamespace(true) => {
+                self.map_child_uid()?;
+                send_message(&self.parent_socket, &Message::IdMapped(true))?;
+                log::info!("Successfully mapped UID/GID for child process");
+            },
+            Message::UserNamespace(false) => {
+                log::debug!("Namespace issue, skipped id mapping");
+                send_message(&self.parent_socket, &Message::IdMapped(false))?;
+            },
+            message => return Err(unexpected_message(message))
+        }
+        match recv_message(&self.parent_socket)? {
+            Message::Ready => log::debug!("Child process is ready to execve"),
+            message => return Err(unexpected_message(message))
+        }
+        self.save_state()
+    }
+    fn hostname(&self) -> &str {
+        self.config.hostname.as_deref().unwrap_or(&self.id)
+    }
+    fn save_state(&self) -> Result<(), ErrorType> {
+        let pid = self.child_proc.as_ref().unwrap().pid;
+        let mut state = ContainerState::new(&self.id, pid, self.bundle.clone(), &self.config);
+        state.ip = self.ip;
+        state.save()
+    }
+    // Creates the container and waits for its command to be executed
+    pub fn launch(&mut self) -> Result<(), ErrorType> {
+        // Blocked from before the clone, so that a Ctrl-C can't kill rucker without cleaning up
+        self.signal_mask = Some(forwarded_signals().thread_swap_mask(SigmaskHow::SIG_BLOCK).map_err(ErrorType::SignalError)?);
+        if self.config.tty {
+            let pty = open_pty()?;
+            self.tty_master = Some(pty.master);
+            self.tty_slave = Some(pty.slave);
+        }
+        if let Err(err) = self.create().and_then(|_| recv_setup_result(&self.parent_socket)) {
+            self.abort();
+            return Err(err);
         }
+        log::info!("Container process started");
         Ok(())
     }
+    // Waits for the container command to exit and cleans up
+    pub fn wait(&mut self) -> Result<i32, ErrorType> {
+        let cgroup = self.config.cgroup_path(&self.id);
+        watch_oom(&self.id, &cgroup);
+        let child_proc = self.child_proc.as_mut().unwrap();
+        let result = match (self.tty_master.take(), self.log_output.take(), self.config.log) {
+            (Some(master), _, _) => child_proc.wait_proxying(&master, self.config.interactive),
+            (None, Some(output), Some(log)) => child_proc.wait_logging(output, log_path(&self.id), log),
+            _ => child_proc.wait_forwarding()
+        };
+        let exit_code = result.inspect_err(|_| self.destroy())?;
+        // Read before the cgroup is removed
+        let oom_kills = oom_kills(&cgroup);
+        if is_oom_killed(exit_code, oom_kills) { log::error!("Container {} was killed by the OOM killer", self.id); }
+        if self.detached { self.record_exit(exit_code, oom_kills); }
+        self.destroy();
+        Ok(exit_code)
+    }
+    fn record_exit(&self, exit_code: i32, oom_kills: u64) {
+        let result = ContainerState::load(&self.id).and_then(|mut state| {
+            state.exit_code = Some(exit_code);
+            state.oom_kills = oom_kills;
+            state.finished = Some(now());
+            state.update()
+        });
+        match result {
+            Ok(()) => (),
+            Err(ErrorType::ContainerNotFoundError(_)) => log::debug!("Container {} was deleted before its exit was recorded", self.id),
+            Err(err) => log::error!("Failed to record the exit status of container {}: {:?}", self.id, err)
+        }
+    }
+    // Kills a child that may still be blocked in the namespace handshake, and cleans up
+    pub fn abort(&mut self) {
+        if let Some(child_proc) = self.child_proc.as_mut() {
+            if let Err(err) = child_proc.kill(Signal::SIGKILL) { log::debug!("Failed to kill child process: {:?}", err); }
+            if let Err(err) = child_proc.wait() { log::error!("Failed to reap child process: {:?}", err); }
+        }
+        self.destroy();
+    }
     pub fn destroy(&mut self) {
         log::info!("cleaning & exit");
-        if let Err(err) = close(self.socket_pair.0.as_raw_fd()) { log::error!("Failed to close child socket when destroying: {:?}", err); }
-        else { log::debug!("Child socket cleaned") }
-        if let Err(err) = close(self.socket_pair.1.as_raw_fd()) { log::error!("Failed to close parent socket when destroying: {:?}", err); }
-        else { log::debug!("Parent socket cleaned") }
-        if let Err(err) = self.clean_cgroup() { log::error!("Failed to clean cgroup when destroying: {:?}", err); }
+        let cgroup = self.config.cgroup_path(&self.id);
+        if let Err(err) = signal_cgroup(&cgroup, Signal::SIGKILL) { log::error!("Failed to kill leftover processes when destroying: {:?}", err); }
+        if let Err(err) = clean_cgroup(&cgroup) { log::error!("Failed to clean cgroup when destroying: {:?}", err); }
+        if let Some(network) = &self.config.network {
+            if let Err(err) = teardown_network(&self.id, network) { log::error!("Failed to remove the network interface when destroying: {:?}", err); }
+        }
+        // A detached container is kept around for `rucker ps` until `rucker delete`
+        if self.detached { return; }
+        if let Err(err) = ContainerState::remove(&self.id) { log::error!("Failed to remove container state when destroying: {:?}", err); }
     }
 }
 
-pub fn run(exec_command: String, mount_dir: PathBuf, addmntpts: Vec<String>, uid: u32) -> Result<(), ErrorType> {
-    let exec_command = CString::new(exec_command).map_err(ErrorType::CStringError)?;
-    let addmntpts = addmntpts.into_iter().map(|s| {
-        let mut pair = s.split(":");
+pub fn run(opt: RunOptions) -> Result<i32, ErrorType> {
+    let argv = opt.command.into_iter()
+        .map(|arg| CString::new(arg).map_err(ErrorType::CStringError))
+        .collect::<Result<Vec<CString>, ErrorType>>()?;
+    let envp = build_environment(&opt.envs, &opt.env_file, opt.uid)?;
+    let mounts = opt.addmntpts.into_iter().map(|s| {
+        let mut pair = s.split(':');
         let src = PathBuf::from(pair.next().unwrap())
             .canonicalize().expect("PanickedException: Cannot canonicalize the source path").to_path_buf();
         let mnt = PathBuf::from(pair.next().unwrap())
             .strip_prefix("/").expect("PanickedException: Cannot strip prefix ('/') from path: mount path should be absolute").to_path_buf();
-        (src, mnt)
+        Mount::bind(src, mnt)
     }).collect();
-    let mut container = Container::new(exec_command, mount_dir, addmntpts, uid)?;
-    container.create().or_else(|err| { container.destroy(); Err(err) })?;
-    container.child_proc.as_mut().unwrap().wait().or_else(|err| { container.destroy(); Err(err) })?;
-    container.destroy();
+    let mut config = ContainerConfig::new(argv, envp, opt.mount_dir);
+    config.mounts = mounts;
+    config.workdir = PathBuf::from("/").join(opt.workdir);
+    config.uid = opt.uid;
+    config.gid = opt.uid;
+    config.init = opt.init;
+    config.resources = Resources::from_options(&opt.resources)?;
+    config.cgroup_parent = parse_cgroup_path(&opt.cgroup_parent)?;
+    config.rlimits = build_rlimits(&opt.ulimits)?;
+    let mode = parse_network_mode(&opt.network)?;
+    let ports = build_ports(&opt.publish)?;
+    if (!ports.is_empty() || opt.ip.is_some()) && mode != NetworkMode::Bridge {
+        return Err(ErrorType::InvalidArgumentError("--publish and --ip only apply to --network bridge".to_string()));
+    }
+    match mode {
+        NetworkMode::None => config.loopback = false,
+        NetworkMode::Loopback => (),
+        NetworkMode::Host => config.namespaces.remove(CloneFlags::CLONE_NEWNET),
+        NetworkMode::Bridge => config.network = Some(NetworkConfig::new(&opt.bridge, &opt.subnet, opt.ip.as_deref(), ports)?)
+    }
+    if let Some(hostname) = &opt.hostname { validate_hostname(hostname)?; }
+    config.hostname = opt.hostname;
+    config.etc_files = Some(EtcFiles::new(&opt.add_hosts, &opt.dns, &opt.dns_search)?);
+    config.tty = opt.tty;
+    config.interactive = opt.interactive;
+    if opt.detach {
+        if opt.tty { return Err(ErrorType::InvalidArgumentError("a detached container can't have a terminal".to_string())); }
+        config.log = Some(LogConfig { max_size: opt.log_max_size, max_files: opt.log_max_files });
+        return run_detached(random_hex_string(), config);
+    }
+    let mut container = Container::new(random_hex_string(), config)?;
+    container.launch()?;
+    container.wait()
+}
+
+// Points stdin, stdout and stderr at /dev/null, so the supervisor does not hold
+// on to the terminal it was started from
+fn detach_stdio() -> Result<(), ErrorType> {
+    let null = open("/dev/null", OFlag::O_RDWR, Mode::empty()).map_err(ErrorType::DetachError)?;
+    for fd in 0..3 { dup2(null, fd).map_err(ErrorType::DetachError)?; }
+    if null > 2 { nix::unistd::close(null).map_err(ErrorType::DetachError)?; }
     Ok(())
 }
+
+// Forks a supervisor that owns the container, waits on it and cleans up when it exits.
+// The CLI only waits for the container command to be executed, and prints its ID.
+// The container is created after the fork so that the CLI holds no end of its socketpair
+fn run_detached(id: String, config: ContainerConfig) -> Result<i32, ErrorType> {
+    let (cli_socket, supervisor_socket) = create_socketpair()?;
+    match unsafe { fork() }.map_err(ErrorType::DetachError)? {
+        ForkResult::Parent { .. } => {
+            drop(supervisor_socket);
+            match recv_message(&cli_socket)? {
+                Message::Ready => {
+                    println!("{}", id);
+                    Ok(0)
+                },
+                message => Err(unexpected_message(message))
+            }
+        },
+        ForkResult::Child => {
+            drop(cli_socket);
+            let result = setsid().map_err(ErrorType::DetachError)
+                .and_then(|_| detach_stdio())
+                .and_then(|_| Container::new(id, config))
+                .and_then(|mut container| container.launch().map(|_| container));
+            let mut container = match result {
+                Ok(container) => container,
+                Err(err) => {
+                    send_message(&supervisor_socket, &Message::SetupFailure(SetupFailure::from(&err)))?;
+                    return Err(err);
+                }
+            };
+            container.detached = true;
+            send_message(&supervisor_socket, &Message::Ready)?;
+            drop(supervisor_socket);
+            container.wait()
+        }
+    }
+}
@@ -0,0 +1,124 @@
+use crate::errors::ErrorType;
+
+use std::ffi::CString;
+use std::fs::read_to_string;
+use std::path::PathBuf;
+
+pub const DEFAULT_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+const DEFAULT_TERM: &str = "xterm";
+
+fn default_environment(uid: u32) -> Vec<(String, String)> {
+    let home = if uid == 0 { "/root" } else { "/" };
+    vec![
+        (String::from("PATH"), String::from(DEFAULT_PATH)),
+        (String::from("HOME"), String::from(home)),
+        (String::from("TERM"), String::from(DEFAULT_TERM)),
+    ]
+}
+
+fn parse_env_entry(entry: &str) -> Option<(String, String)> {
+    match entry.split_once('=') {
+        Some(("", _)) => {
+            log::warn!("Ignoring environment variable without a name: {}", entry);
+            None
+        },
+        Some((key, value)) => Some((key.to_string(), value.to_string())),
+        // `-e KEY` passes the variable through from the host, if it is set
+        None => std::env::var(entry).ok().map(|value| (entry.to_string(), value))
+    }
+}
+
+fn set_variable(environ: &mut Vec<(String, String)>, (key, value): (String, String)) {
+    match environ.iter_mut().find(|(k, _)| *k == key) {
+        Some(var) => var.1 = value,
+        None => environ.push((key, value))
+    }
+}
+
+pub fn build_environment(envs: &[String], env_files: &[PathBuf], uid: u32) -> Result<Vec<CString>, ErrorType> {
+    let mut environ = default_environment(uid);
+    for env_file in env_files.iter() {
+        log::debug!("Reading environment variables from {}", env_file.display());
+        let content = read_to_string(env_file).map_err(ErrorType::EnvFileError)?;
+        content.lines().map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .filter_map(parse_env_entry)
+            .for_each(|var| set_variable(&mut environ, var));
+    }
+    envs.iter().map(String::as_str)
+        .filter_map(parse_env_entry)
+        .for_each(|var| set_variable(&mut environ, var));
+    environ.into_iter()
+        .map(|(key, value)| CString::new(format!("{}={}", key, value)).map_err(ErrorType::CStringError))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::{remove_file, write};
+
+    fn env_file(name: &str, content: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("rucker-test-{}-{}.env", name, std::process::id()));
+        write(&path, content).expect("Failed to write the env file");
+        path
+    }
+
+    fn variables(environ: Vec<CString>) -> Vec<String> {
+        environ.into_iter().map(|var| var.into_string().unwrap()).collect()
+    }
+
+    #[test]
+    fn entry_keeps_quotes_and_equal_signs() {
+        assert_eq!(parse_env_entry("KEY=\"a b\""), Some(("KEY".to_string(), "\"a b\"".to_string())));
+        assert_eq!(parse_env_entry("KEY='a=b'"), Some(("KEY".to_string(), "'a=b'".to_string())));
+        assert_eq!(parse_env_entry("KEY="), Some(("KEY".to_string(), String::new())));
+    }
+
+    #[test]
+    fn entry_without_name_is_ignored() {
+        assert_eq!(parse_env_entry("=value"), None);
+    }
+
+    #[test]
+    fn entry_without_value_comes_from_host() {
+        std::env::set_var("RUCKER_TEST_PASSED", "host value");
+        assert_eq!(parse_env_entry("RUCKER_TEST_PASSED"), Some(("RUCKER_TEST_PASSED".to_string(), "host value".to_string())));
+        std::env::remove_var("RUCKER_TEST_UNSET");
+        assert_eq!(parse_env_entry("RUCKER_TEST_UNSET"), None);
+    }
+
+    #[test]
+    fn defaults_depend_on_uid() {
+        let root = variables(build_environment(&[], &[], 0).unwrap());
+        assert_eq!(root, [format!("PATH={}", DEFAULT_PATH), "HOME=/root".to_string(), "TERM=xterm".to_string()]);
+        let user = variables(build_environment(&[], &[], 1000).unwrap());
+        assert!(user.contains(&"HOME=/".to_string()));
+    }
+
+    #[test]
+    fn env_file_skips_comments_and_blank_lines() {
+        let path = env_file("comments", "# a comment\n\n  FOO=bar  \n  # indented comment\nBAZ=\"quoted value\"\n=nameless\n");
+        let environ = build_environment(&[], std::slice::from_ref(&path), 0);
+        let _ = remove_file(&path);
+        let environ = variables(environ.unwrap());
+        assert_eq!(&environ[3..], ["FOO=bar", "BAZ=\"quoted value\""]);
+    }
+
+    #[test]
+    fn later_values_override_earlier_ones() {
+        let path = env_file("override", "FOO=file\nTERM=vt100\nBAR=file\n");
+        let envs = ["FOO=option".to_string(), "PATH=/bin".to_string()];
+        let environ = build_environment(&envs, std::slice::from_ref(&path), 0);
+        let _ = remove_file(&path);
+        let environ = variables(environ.unwrap());
+        assert_eq!(environ, ["PATH=/bin", "HOME=/root", "TERM=vt100", "FOO=option", "BAR=file"]);
+    }
+
+    #[test]
+    fn missing_env_file_is_an_error() {
+        let path = std::env::temp_dir().join("rucker-test-missing.env");
+        assert!(matches!(build_environment(&[], &[path], 0), Err(ErrorType::EnvFileError(_))));
+    }
+}
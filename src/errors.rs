@@ -1,23 +1,152 @@
+use nix::errno::Errno;
+
+use std::path::PathBuf;
+
+// Exit codes reserved for rucker's own failures, following docker's convention
+pub const EXIT_SETUP_FAILURE: i32 = 125;
+pub const EXIT_NOT_EXECUTABLE: i32 = 126;
+pub const EXIT_NOT_FOUND: i32 = 127;
+// A container killed by signal N exits with EXIT_SIGNAL_OFFSET + N
+pub const EXIT_SIGNAL_OFFSET: i32 = 128;
+
+// A failure of the container setup, reported by the child process to the parent
 #[derive(Debug)]
+pub struct SetupFailure {
+    pub step: String,
+    pub errno: Errno,
+    pub path: Option<PathBuf>,
+    pub exit_code: i32
+}
+
+#[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum ErrorType {
     CStringError(std::ffi::NulError),
     SocketPairError(nix::errno::Errno),
     SocketSendError(nix::errno::Errno),
     SocketRecvError(nix::errno::Errno),
-    SocketCloseError(nix::errno::Errno),
     ChildProcessError(nix::errno::Errno),
     ExecveError(nix::errno::Errno),
     WaitingError(nix::errno::Errno),
     HostnameError(nix::errno::Errno),
-    MountError(nix::errno::Errno),
-    UnmountError(nix::errno::Errno),
-    PivotRootError(nix::errno::Errno),
-    ChDirError(nix::errno::Errno),
+    MountError(nix::errno::Errno, PathBuf),
+    UnmountError(nix::errno::Errno, PathBuf),
+    PivotRootError(nix::errno::Errno, PathBuf),
+    ChDirError(nix::errno::Errno, PathBuf),
     DirectoryError(std::io::Error),
     FileError(std::io::Error),
     UserSysError(nix::errno::Errno),
     CapabilityError(std::io::Error),
     SyscallError(syscallz::Error),
     CgroupError(cgroups_rs::error::Error),
-    RlimitError(std::io::Error)
+    CgroupFileError(std::io::Error, PathBuf),
+    // The request that failed, e.g. "create link rucker0"
+    NetlinkError(std::io::Error, String),
+    // The published port, e.g. "8080:80/tcp"
+    ProxyError(std::io::Error, String),
+    RlimitError(std::io::Error),
+    EnvFileError(std::io::Error),
+    CommandNotFoundError(String),
+    CommandNotExecutableError(String),
+    SignalError(nix::errno::Errno),
+    SocketOptionError(nix::errno::Errno),
+    IpcProtocolError(String),
+    ExecFifoError(nix::errno::Errno),
+    NamespaceError(nix::errno::Errno, PathBuf),
+    StateError(std::io::Error),
+    JsonError(serde_json::Error),
+    SpecError(String),
+    InvalidArgumentError(String),
+    InvalidStateError(String),
+    ContainerNotFoundError(String),
+    ContainerExistsError(String),
+    ContainerSetupError(SetupFailure),
+    DetachError(nix::errno::Errno),
+    TtyError(nix::errno::Errno),
+    LogError(std::io::Error)
+}
+
+impl ErrorType {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ErrorType::CommandNotExecutableError(_) => EXIT_NOT_EXECUTABLE,
+            ErrorType::CommandNotFoundError(_) => EXIT_NOT_FOUND,
+            ErrorType::ContainerSetupError(failure) => failure.exit_code,
+            _ => EXIT_SETUP_FAILURE
+        }
+    }
+
+    pub fn step(&self) -> &'static str {
+        match self {
+            ErrorType::HostnameError(_) => "sethostname",
+            ErrorType::NamespaceError(..) => "setns",
+            ErrorType::MountError(..) => "mount",
+            ErrorType::UnmountError(..) => "umount",
+            ErrorType::PivotRootError(..) => "pivot_root",
+            ErrorType::ChDirError(..) => "chdir",
+            ErrorType::DirectoryError(_) => "mkdir",
+            ErrorType::UserSysError(_) => "setresuid",
+            ErrorType::CapabilityError(_) => "capabilities",
+            ErrorType::RlimitError(_) => "setrlimit",
+            ErrorType::NetlinkError(..) => "network",
+            ErrorType::SyscallError(_) => "seccomp",
+            ErrorType::CommandNotFoundError(_) | ErrorType::CommandNotExecutableError(_) => "command lookup",
+            ErrorType::ExecveError(_) => "execve",
+            ErrorType::ExecFifoError(_) => "exec fifo",
+            ErrorType::DetachError(_) => "detach",
+            ErrorType::TtyError(_) => "tty",
+            ErrorType::LogError(_) => "logging",
+            ErrorType::SocketSendError(_) | ErrorType::SocketRecvError(_)
+            | ErrorType::SocketOptionError(_) | ErrorType::IpcProtocolError(_) => "ipc",
+            _ => "setup"
+        }
+    }
+
+    pub fn errno(&self) -> Errno {
+        match self {
+            ErrorType::SocketPairError(err) | ErrorType::SocketSendError(err) | ErrorType::SocketRecvError(err)
+            | ErrorType::ChildProcessError(err) | ErrorType::ExecveError(err)
+            | ErrorType::WaitingError(err) | ErrorType::HostnameError(err) | ErrorType::UserSysError(err)
+            | ErrorType::SignalError(err) | ErrorType::SocketOptionError(err) | ErrorType::ExecFifoError(err)
+            | ErrorType::DetachError(err) | ErrorType::TtyError(err) => *err,
+            ErrorType::MountError(err, _) | ErrorType::UnmountError(err, _)
+            | ErrorType::PivotRootError(err, _) | ErrorType::ChDirError(err, _)
+            | ErrorType::NamespaceError(err, _) => *err,
+            ErrorType::DirectoryError(err) | ErrorType::FileError(err) | ErrorType::CapabilityError(err)
+            | ErrorType::RlimitError(err) | ErrorType::EnvFileError(err) | ErrorType::StateError(err)
+            | ErrorType::LogError(err) | ErrorType::CgroupFileError(err, _)
+            | ErrorType::NetlinkError(err, _) | ErrorType::ProxyError(err, _) => Errno::from_raw(err.raw_os_error().unwrap_or(0)),
+            ErrorType::CStringError(_) => Errno::EINVAL,
+            ErrorType::IpcProtocolError(_) => Errno::EBADMSG,
+            ErrorType::CommandNotFoundError(_) => Errno::ENOENT,
+            ErrorType::CommandNotExecutableError(_) => Errno::EACCES,
+            ErrorType::ContainerSetupError(failure) => failure.errno,
+            _ => Errno::UnknownErrno
+        }
+    }
+
+    pub fn path(&self) -> Option<PathBuf> {
+        match self {
+            ErrorType::MountError(_, path) | ErrorType::UnmountError(_, path)
+            | ErrorType::PivotRootError(_, path) | ErrorType::ChDirError(_, path)
+            | ErrorType::NamespaceError(_, path) | ErrorType::CgroupFileError(_, path) => Some(path.clone()),
+            ErrorType::CommandNotFoundError(command) | ErrorType::CommandNotExecutableError(command) => Some(PathBuf::from(command)),
+            ErrorType::ContainerSetupError(failure) => failure.path.clone(),
+            _ => None
+        }
+    }
+}
+
+impl From<&ErrorType> for SetupFailure {
+    fn from(err_type: &ErrorType) -> SetupFailure {
+        if let ErrorType::ContainerSetupError(failure) = err_type {
+            return SetupFailure { step: failure.step.clone(), errno: failure.errno, path: failure.path.clone(), exit_code: failure.exit_code };
+        }
+        SetupFailure {
+            step: err_type.step().to_string(),
+            errno: err_type.errno(),
+            path: err_type.path(),
+            exit_code: err_type.exit_code()
+        }
+    }
 }
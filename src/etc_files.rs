@@ -0,0 +1,105 @@
+use crate::config::Mount;
+use crate::errors::ErrorType;
+use crate::state::state_dir;
+
+use std::fs::{create_dir_all, read_to_string, write};
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::PathBuf;
+
+const HOSTS: &str = "hosts";
+const HOSTNAME: &str = "hostname";
+const RESOLV_CONF: &str = "resolv.conf";
+const HOST_RESOLV_CONF: &str = "/etc/resolv.conf";
+// Used when the host has no nameserver the container can reach
+const DEFAULT_NAMESERVERS: [&str; 2] = ["8.8.8.8", "8.8.4.4"];
+// Where a hostname resolves to without an address on the bridge, as on Debian
+const LOOPBACK_HOSTNAME_ADDRESS: &str = "127.0.1.1";
+const MAX_HOSTNAME: usize = 64;
+
+// What goes in the /etc/hosts and /etc/resolv.conf of a container besides the defaults
+pub struct EtcFiles {
+    pub extra_hosts: Vec<(String, IpAddr)>,
+    // Replace the host's nameservers and search domains when given
+    pub dns: Vec<IpAddr>,
+    pub dns_search: Vec<String>
+}
+
+pub fn validate_hostname(hostname: &str) -> Result<(), ErrorType> {
+    let valid = !hostname.is_empty() && hostname.len() <= MAX_HOSTNAME && !hostname.starts_with(['-', '.'])
+        && hostname.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.');
+    if valid { Ok(()) } else { Err(ErrorType::InvalidArgumentError(format!("invalid hostname {:?}", hostname))) }
+}
+
+impl EtcFiles {
+    // From the `run` options: "<name>:<address>" hosts, nameserver addresses and domains
+    pub fn new(add_hosts: &[String], dns: &[String], dns_search: &[String]) -> Result<EtcFiles, ErrorType> {
+        let invalid_address = |address: &str| ErrorType::InvalidArgumentError(format!("invalid address {:?}", address));
+        let mut extra_hosts = Vec::new();
+        for host in add_hosts.iter() {
+            let (name, address) = host.split_once(':')
+                .ok_or_else(|| ErrorType::InvalidArgumentError(format!("invalid host {:?}, expected <name>:<address>", host)))?;
+            validate_hostname(name)?;
+            extra_hosts.push((name.to_string(), address.parse().map_err(|_| invalid_address(address))?));
+        }
+        let dns = dns.iter().map(|address| address.parse().map_err(|_| invalid_address(address)))
+            .collect::<Result<Vec<IpAddr>, ErrorType>>()?;
+        for domain in dns_search.iter() { validate_hostname(domain)?; }
+        Ok(EtcFiles { extra_hosts, dns, dns_search: dns_search.to_vec() })
+    }
+
+    fn hosts(&self, hostname: &str, address: Option<Ipv4Addr>) -> String {
+        let address = address.map(|address| address.to_string()).unwrap_or(LOOPBACK_HOSTNAME_ADDRESS.to_string());
+        let mut hosts = format!("127.0.0.1\tlocalhost\n::1\tlocalhost ip6-localhost ip6-loopback\n{}\t{}\n", address, hostname);
+        for (name, address) in self.extra_hosts.iter() { hosts.push_str(&format!("{}\t{}\n", address, name)); }
+        hosts
+    }
+
+    // Based on the host's, whose loopback nameservers, e.g. systemd-resolved's, can't be reached
+    // from another network namespace
+    fn resolv_conf(&self, host_network: bool) -> String {
+        let host = read_to_string(HOST_RESOLV_CONF).unwrap_or_default();
+        let lines_of = |keyword: &str| host.lines()
+            .filter_map(|line| line.strip_prefix(keyword).and_then(|rest| rest.strip_prefix([' ', '\t'])))
+            .map(|rest| rest.trim().to_string())
+            .collect::<Vec<String>>();
+        let mut nameservers: Vec<String> = if self.dns.is_empty() {
+            lines_of("nameserver").into_iter()
+                .filter(|nameserver| host_network || !nameserver.parse::<IpAddr>().is_ok_and(|address| address.is_loopback()))
+                .collect()
+        } else {
+            self.dns.iter().map(IpAddr::to_string).collect()
+        };
+        if nameservers.is_empty() { nameservers = DEFAULT_NAMESERVERS.iter().map(|nameserver| nameserver.to_string()).collect(); }
+        let search = if self.dns_search.is_empty() { lines_of("search") } else { vec![self.dns_search.join(" ")] };
+        let mut resolv_conf = String::new();
+        for domains in search.iter() { resolv_conf.push_str(&format!("search {}\n", domains)); }
+        for nameserver in nameservers.iter() { resolv_conf.push_str(&format!("nameserver {}\n", nameserver)); }
+        for options in lines_of("options").iter() { resolv_conf.push_str(&format!("options {}\n", options)); }
+        resolv_conf
+    }
+}
+
+fn etc_file_path(id: &str, name: &str) -> PathBuf {
+    state_dir(id).join(name)
+}
+
+// Written to the state directory before the container is cloned, the address of the container
+// being filled in by write_hosts once its network is set up
+pub fn write_etc_files(id: &str, hostname: &str, files: &EtcFiles, host_network: bool) -> Result<(), ErrorType> {
+    create_dir_all(state_dir(id)).map_err(ErrorType::StateError)?;
+    write(etc_file_path(id, HOSTNAME), format!("{}\n", hostname)).map_err(ErrorType::FileError)?;
+    write(etc_file_path(id, RESOLV_CONF), files.resolv_conf(host_network)).map_err(ErrorType::FileError)?;
+    write_hosts(id, hostname, None, files)
+}
+
+// Rewritten in place, so that the bind mount of the container sees the new content
+pub fn write_hosts(id: &str, hostname: &str, address: Option<Ipv4Addr>, files: &EtcFiles) -> Result<(), ErrorType> {
+    write(etc_file_path(id, HOSTS), files.hosts(hostname, address)).map_err(ErrorType::FileError)
+}
+
+// The generated files bind-mounted over those of the rootfs
+pub fn etc_mounts(id: &str) -> Vec<Mount> {
+    [HOSTS, HOSTNAME, RESOLV_CONF].iter()
+        .map(|name| Mount::bind(etc_file_path(id, name), PathBuf::from("/etc").join(name)))
+        .collect()
+}
@@ -0,0 +1,85 @@
+use crate::errors::ErrorType;
+use crate::state::{ContainerState, Status};
+use crate::childproc::ChildProcess;
+use crate::capabilities::CAPABILITIES_DROP;
+use crate::syscalls::SeccompProfile;
+use crate::cgroup::join_cgroup;
+use crate::environ::build_environment;
+use crate::internal::{resolve_command, drop_capabilities, restrict_syscalls};
+use crate::namespace::switch_user;
+use crate::ExecOptions;
+
+use nix::fcntl::{open, OFlag};
+use nix::sched::{setns, CloneFlags};
+use nix::sys::stat::Mode;
+use nix::unistd::{chdir, execve, fork, getpid, ForkResult};
+
+use std::ffi::CString;
+use std::fs::read_link;
+use std::os::fd::{FromRawFd, OwnedFd};
+use std::path::PathBuf;
+
+// The container's namespaces are owned by the host's user namespace, so its own
+// user namespace is joined last: the capabilities to join the others are lost then
+const NAMESPACES: [(&str, CloneFlags); 7] = [
+    ("cgroup", CloneFlags::CLONE_NEWCGROUP),
+    ("ipc", CloneFlags::CLONE_NEWIPC),
+    ("uts", CloneFlags::CLONE_NEWUTS),
+    ("net", CloneFlags::CLONE_NEWNET),
+    ("pid", CloneFlags::CLONE_NEWPID),
+    ("mnt", CloneFlags::CLONE_NEWNS),
+    ("user", CloneFlags::CLONE_NEWUSER)
+];
+
+// Namespaces the container shares with the host can't be joined again
+// (setns fails with EINVAL for the user namespace we are already in)
+fn is_shared(pid: i32, name: &str) -> bool {
+    let theirs = read_link(format!("/proc/{}/ns/{}", pid, name));
+    let ours = read_link(format!("/proc/self/ns/{}", name));
+    matches!((theirs, ours), (Ok(theirs), Ok(ours)) if theirs == ours)
+}
+
+pub fn exec(opt: ExecOptions) -> Result<i32, ErrorType> {
+    let state = ContainerState::load(&opt.id)?;
+    let status = state.status();
+    if status != Status::Running {
+        return Err(ErrorType::InvalidStateError(format!("container {} is {}, not running", opt.id, status)));
+    }
+    let argv = opt.command.into_iter()
+        .map(|arg| CString::new(arg).map_err(ErrorType::CStringError))
+        .collect::<Result<Vec<CString>, ErrorType>>()?;
+    let envp = build_environment(&opt.envs, &opt.env_file, state.uid)?;
+
+    // Before joining the mount namespace, where the host's cgroup filesystem may not be visible
+    join_cgroup(&state.cgroup, getpid())?;
+    // All opened up front, as the host's /proc is gone once in the mount namespace
+    let mut namespaces = Vec::new();
+    for (name, flag) in NAMESPACES.iter() {
+        if is_shared(state.pid, name) { continue; }
+        let path = PathBuf::from(format!("/proc/{}/ns/{}", state.pid, name));
+        let fd = open(&path, OFlag::O_RDONLY | OFlag::O_CLOEXEC, Mode::empty())
+            .map_err(|err| ErrorType::NamespaceError(err, path.clone()))?;
+        namespaces.push((unsafe { OwnedFd::from_raw_fd(fd) }, *flag, path));
+    }
+    for (fd, flag, path) in namespaces {
+        setns(fd, flag).map_err(|err| ErrorType::NamespaceError(err, path))?;
+    }
+    log::debug!("Joined the namespaces of container {}", state.id);
+
+    // Joining a pid namespace only applies to the children of the caller
+    match unsafe { fork() }.map_err(ErrorType::ChildProcessError)? {
+        ForkResult::Parent { child } => ChildProcess::of(child).wait(),
+        ForkResult::Child => {
+            // Dropping from the bounding set takes CAP_SETPCAP, which a non-root user no longer has
+            drop_capabilities(&CAPABILITIES_DROP)?;
+            switch_user(state.uid, state.gid)?;
+            let workdir = opt.workdir.unwrap_or(state.workdir);
+            chdir(&workdir).map_err(|err| ErrorType::ChDirError(err, workdir.clone()))?;
+            restrict_syscalls(&SeccompProfile::default())?;
+            let command = resolve_command(&argv[0], &envp)?;
+            log::info!("Executing <command:{:?}> in container {}", argv, state.id);
+            execve::<CString, CString>(&command, &argv, &envp).map_err(ErrorType::ExecveError)?;
+            Ok(0)
+        }
+    }
+}
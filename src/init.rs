@@ -0,0 +1,63 @@
+use crate::errors::{ErrorType, EXIT_SIGNAL_OFFSET};
+
+use nix::errno::Errno;
+use nix::sys::signal::{kill, SigSet, SigmaskHow, Signal};
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::{execve, fork, ForkResult, Pid};
+
+use std::ffi::CString;
+
+// Raised by a fault of the init itself rather than sent to it, so they are left unblocked
+const SYNCHRONOUS_SIGNALS: [Signal; 7] = [
+    Signal::SIGFPE, Signal::SIGILL, Signal::SIGSEGV, Signal::SIGBUS,
+    Signal::SIGABRT, Signal::SIGTRAP, Signal::SIGSYS
+];
+
+fn handled_signals() -> SigSet {
+    let mut signals = SigSet::all();
+    for signal in SYNCHRONOUS_SIGNALS.iter() { signals.remove(*signal); }
+    signals
+}
+
+// Reaps every process that exited, returns the exit status of the workload once it did
+fn reap(workload: Pid) -> Result<Option<i32>, ErrorType> {
+    let mut exit_code = None;
+    loop {
+        match waitpid(Pid::from_raw(-1), Some(WaitPidFlag::WNOHANG)) {
+            Ok(WaitStatus::Exited(pid, code)) if pid == workload => exit_code = Some(code),
+            Ok(WaitStatus::Signaled(pid, signal, _)) if pid == workload => exit_code = Some(EXIT_SIGNAL_OFFSET + signal as i32),
+            Ok(WaitStatus::StillAlive) | Err(Errno::ECHILD) => return Ok(exit_code),
+            Ok(status) => log::debug!("Reaped {:?}", status),
+            Err(err) => return Err(ErrorType::WaitingError(err))
+        }
+    }
+}
+
+// The init of `run --init`, run by the container process itself in place of the execve:
+// PID 1 of the container, it spawns the workload, forwards it the signals it receives and
+// reaps the orphans the workload leaves behind. Nothing is executed from the rootfs but the
+// workload, which may lack what the rucker binary is linked against.
+// Signals are blocked and taken with sigwait, as blocked signals are not discarded
+// like the ones an init has no handler for
+pub fn init(executable: &CString, argv: &[CString], envp: &[CString]) -> Result<i32, ErrorType> {
+    let signals = handled_signals();
+    let original_mask = signals.thread_swap_mask(SigmaskHow::SIG_BLOCK).map_err(ErrorType::SignalError)?;
+    match unsafe { fork() }.map_err(ErrorType::ChildProcessError)? {
+        ForkResult::Child => {
+            original_mask.thread_set_mask().map_err(ErrorType::SignalError)?;
+            execve(executable, argv, envp).map_err(ErrorType::ExecveError)?;
+            Ok(0)
+        },
+        ForkResult::Parent { child } => {
+            log::debug!("Init started the workload (pid {})", child);
+            loop {
+                match signals.wait().map_err(ErrorType::SignalError)? {
+                    Signal::SIGCHLD => if let Some(exit_code) = reap(child)? { return Ok(exit_code); },
+                    signal => if let Err(err) = kill(child, signal) {
+                        log::debug!("Failed to forward {} to the workload: {:?}", signal, err);
+                    }
+                }
+            }
+        }
+    }
+}
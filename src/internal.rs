@@ -1,17 +1,33 @@
 use crate::errors::ErrorType;
 use crate::utils::random_hex_string;
-use crate::capabilities::CAPABILITIES_DROP;
-use crate::syscalls::{SYSCALLS_REFUSED, SYSCALLS_CONDITIONALLY_REFUSED};
+use crate::syscalls::{SeccompProfile, SeccompRule};
+use crate::environ::DEFAULT_PATH;
+use crate::config::Mount;
 
-use nix::unistd::{sethostname, pivot_root, chdir};
+use nix::unistd::{sethostname, pivot_root, chdir, access, read, AccessFlags};
+use nix::sched::{setns, CloneFlags};
+use nix::poll::{poll, PollFd, PollFlags, PollTimeout};
+use nix::fcntl::{open, OFlag};
+use nix::sys::stat::Mode;
 use nix::mount::{mount, MsFlags, umount2, MntFlags};
-use capctl::caps::FullCapState;
-use syscallz::{Context, Action, Syscall, Comparator, Cmp};
+use nix::errno::Errno;
+use capctl::caps::{bounding, Cap, CapState};
+use syscallz::Context;
 
-use std::path::PathBuf;
-use std::fs::{create_dir_all, remove_dir};
+use std::ffi::CString;
+use std::os::fd::{AsFd, AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+use std::fs::{create_dir_all, remove_dir, File, FileType};
 
-const EPERM: u16 = 1;
+pub fn join_namespace(flag: CloneFlags, path: &PathBuf) -> Result<(), ErrorType> {
+    log::debug!("Joining namespace {}", path.display());
+    let fd = open(path, OFlag::O_RDONLY | OFlag::O_CLOEXEC, Mode::empty())
+        .map_err(|err| ErrorType::NamespaceError(err, path.clone()))?;
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+    setns(fd, flag).map_err(|err| ErrorType::NamespaceError(err, path.clone()))?;
+    Ok(())
+}
 
 pub fn set_hostname(hostname: &String) -> Result<(), ErrorType> {
     sethostname(hostname).map_err(ErrorType::HostnameError)?;
@@ -21,61 +37,169 @@ pub fn set_hostname(hostname: &String) -> Result<(), ErrorType> {
 fn mount_directory(source: Option<&PathBuf>, mount_point: &PathBuf, flags: Vec<MsFlags>) -> Result<(), ErrorType> {
     let mut ms_flags = MsFlags::empty();
     for f in flags.iter() { ms_flags.insert(*f); }
-    mount::<PathBuf, PathBuf, PathBuf, PathBuf>(source, mount_point, None, ms_flags, None).map_err(ErrorType::MountError)?;
+    mount::<PathBuf, PathBuf, PathBuf, PathBuf>(source, mount_point, None, ms_flags, None)
+        .map_err(|err| ErrorType::MountError(err, mount_point.clone()))?;
+    Ok(())
+}
+
+fn mount_filesystem(new_root: &Path, mnt: &Mount) -> Result<(), ErrorType> {
+    let target = new_root.join(mnt.destination.strip_prefix("/").unwrap_or(&mnt.destination));
+    log::debug!("Mounting {:?} on {}", mnt.source, target.display());
+    if mnt.flags.contains(MsFlags::MS_BIND) && mnt.source.as_ref().is_some_and(|source| !source.is_dir()) {
+        // A file can only be bind-mounted over a file
+        if let Some(parent) = target.parent() { create_dir_all(parent).map_err(ErrorType::DirectoryError)?; }
+        if !target.exists() { File::create(&target).map_err(ErrorType::DirectoryError)?; }
+    } else {
+        create_dir_all(&target).map_err(ErrorType::DirectoryError)?;
+    }
+    // Propagation flags cannot be combined with anything but MS_REC
+    let propagation = MsFlags::MS_SHARED | MsFlags::MS_SLAVE | MsFlags::MS_PRIVATE | MsFlags::MS_UNBINDABLE;
+    let flags = if mnt.flags.contains(MsFlags::MS_BIND) { mnt.flags } else { mnt.flags - propagation };
+    let source = mnt.source.clone().or_else(|| mnt.fstype.as_ref().map(PathBuf::from));
+    mount(source.as_ref(), &target, mnt.fstype.as_deref(), flags, mnt.data.as_deref())
+        .map_err(|err| ErrorType::MountError(err, mnt.destination.clone()))?;
+    // Bind mounts ignore every flag but MS_REC until remounted
+    let remount_flags = mnt.flags & (MsFlags::MS_RDONLY | MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC);
+    if mnt.flags.contains(MsFlags::MS_BIND) && !remount_flags.is_empty() {
+        mount::<PathBuf, PathBuf, str, str>(None, &target, None, MsFlags::MS_REMOUNT | MsFlags::MS_BIND | remount_flags, None)
+            .map_err(|err| ErrorType::MountError(err, mnt.destination.clone()))?;
+    }
     Ok(())
 }
 
-pub fn set_mountpoint(mount_dir: &PathBuf, addmntpts: &Vec<(PathBuf, PathBuf)>) -> Result<(), ErrorType> {
+// The type of a file of the rootfs, whose path is resolved without following symlinks, as they
+// would resolve against the host. A path going through one gives the type of the symlink
+fn rootfs_file_type(new_root: &Path, destination: &Path) -> Option<FileType> {
+    let components: Vec<Component> = destination.strip_prefix("/").unwrap_or(destination).components().collect();
+    let mut path = new_root.to_path_buf();
+    for (i, component) in components.iter().enumerate() {
+        path.push(component);
+        let file_type = path.symlink_metadata().ok()?.file_type();
+        if i + 1 == components.len() || file_type.is_symlink() { return Some(file_type); }
+        if !file_type.is_dir() { return None; }
+    }
+    None
+}
+
+pub fn set_mountpoint(mount_dir: &PathBuf, etc_mounts: &[Mount], mounts: &[Mount], readonly: bool) -> Result<(), ErrorType> {
     mount_directory(None, &PathBuf::from("/"), vec![MsFlags::MS_REC, MsFlags::MS_PRIVATE])?;
     let new_root = PathBuf::from(format!("/tmp/rucker-root-mntpt-{}", random_hex_string()));
     log::debug!("Setting root mount point: {}", new_root.as_path().to_str().unwrap());
     create_dir_all(&new_root).map_err(ErrorType::DirectoryError)?;
-    mount_directory(Some(&mount_dir), &new_root, vec![MsFlags::MS_BIND, MsFlags::MS_PRIVATE])?;
+    mount_directory(Some(mount_dir), &new_root, vec![MsFlags::MS_BIND, MsFlags::MS_PRIVATE])?;
 
-    log::debug!("Setting additionnal mount points");
-    for (inpath, mntpath) in addmntpts.iter() {
-        let outpath = new_root.join(mntpath);
-        create_dir_all(&outpath).map_err(ErrorType::DirectoryError)?;
-        mount_directory(Some(inpath), &outpath, vec![MsFlags::MS_PRIVATE, MsFlags::MS_BIND])?;
+    // Only mounted over files of the rootfs, so as not to create them there
+    for mnt in etc_mounts.iter() {
+        match rootfs_file_type(&new_root, &mnt.destination) {
+            Some(file_type) if file_type.is_file() => mount_filesystem(&new_root, mnt)?,
+            Some(_) => log::warn!("{} of the rootfs is not a regular file, e.g. a symlink, leaving the image's one", mnt.destination.display()),
+            None => log::debug!("No {} in the rootfs, leaving it out", mnt.destination.display())
+        }
     }
 
+    log::debug!("Setting additionnal mount points");
+    for mnt in mounts.iter() { mount_filesystem(&new_root, mnt)?; }
+
     log::debug!("Pivoting root");
     let old_root_tail = format!("oldroot.{}", random_hex_string());
     let put_old = new_root.join(PathBuf::from(old_root_tail.clone()));
     create_dir_all(&put_old).map_err(ErrorType::DirectoryError)?;
-    pivot_root(&new_root, &put_old).map_err(ErrorType::PivotRootError)?;
+    pivot_root(&new_root, &put_old).map_err(|err| ErrorType::PivotRootError(err, new_root.clone()))?;
 
     log::debug!("Unmounting old root and removing directories");
     let old_root = PathBuf::from(format!("/{}", old_root_tail));
-    chdir(&PathBuf::from("/")).map_err(ErrorType::ChDirError)?;
-    umount2(&old_root, MntFlags::MNT_DETACH).map_err(ErrorType::UnmountError)?;
-    remove_dir(&old_root.as_path()).map_err(ErrorType::DirectoryError)?;
+    chdir(&PathBuf::from("/")).map_err(|err| ErrorType::ChDirError(err, PathBuf::from("/")))?;
+    umount2(&old_root, MntFlags::MNT_DETACH).map_err(|err| ErrorType::UnmountError(err, old_root.clone()))?;
+    remove_dir(old_root.as_path()).map_err(ErrorType::DirectoryError)?;
 
+    if readonly {
+        log::debug!("Remounting root as read-only");
+        mount_directory(None, &PathBuf::from("/"), vec![MsFlags::MS_REMOUNT, MsFlags::MS_BIND, MsFlags::MS_RDONLY])?;
+    }
     Ok(())
 }
 
-pub fn drop_capabilities() -> Result<(), ErrorType> {
-    let mut caps = FullCapState::get_current().map_err(ErrorType::CapabilityError)?;
-    caps.bounding.drop_all(CAPABILITIES_DROP.iter().map(|&cap| cap));
-    caps.inheritable.drop_all(CAPABILITIES_DROP.iter().map(|&cap| cap));
-    log::info!("Successfully dropped unwanted capabilities");
+pub fn set_workdir(workdir: &PathBuf) -> Result<(), ErrorType> {
+    log::debug!("Setting working directory: {}", workdir.display());
+    create_dir_all(workdir).map_err(ErrorType::DirectoryError)?;
+    chdir(workdir).map_err(|err| ErrorType::ChDirError(err, workdir.clone()))?;
     Ok(())
 }
 
-fn refuse_syscall(ctx: &mut Context, syscall: &Syscall) -> Result<(), ErrorType> {
-    ctx.set_action_for_syscall(Action::Errno(EPERM), *syscall).map_err(ErrorType::SyscallError)
+// Blocks until `rucker start` opens the exec fifo for writing and writes a byte.
+// Unlike read, poll does not report a fifo that never had a writer as hung up
+pub fn wait_for_start(exec_fifo: &OwnedFd) -> Result<(), ErrorType> {
+    log::debug!("Waiting for the container to be started");
+    let mut fds = [PollFd::new(exec_fifo.as_fd(), PollFlags::POLLIN)];
+    loop {
+        match poll(&mut fds, PollTimeout::NONE) {
+            Err(Errno::EINTR) => continue,
+            result => { result.map_err(ErrorType::ExecFifoError)?; break; }
+        }
+    }
+    let mut byte = [0u8; 1];
+    read(exec_fifo.as_raw_fd(), &mut byte).map_err(ErrorType::ExecFifoError)?;
+    Ok(())
+}
+
+fn is_executable(path: &Path) -> bool {
+    path.is_file() && access(path, AccessFlags::X_OK).is_ok()
 }
 
-fn refuse_conditionally(ctx: &mut Context, syscall: &Syscall, ind: u32, biteq: u64)-> Result<(), ErrorType> {
-    ctx.set_rule_for_syscall(Action::Errno(EPERM), *syscall,
-        &[Comparator::new(ind, Cmp::MaskedEq, biteq, Some(biteq))]).map_err(ErrorType::SyscallError)
+// Must be called after pivot_root and the uid switch, so that the lookup
+// sees the container filesystem with the permissions of the container user
+pub fn resolve_command(command: &CString, envp: &[CString]) -> Result<CString, ErrorType> {
+    let command_str = command.to_string_lossy().into_owned();
+    if command_str.contains('/') {
+        let path = Path::new(&command_str);
+        if !path.exists() { return Err(ErrorType::CommandNotFoundError(command_str)); }
+        if !is_executable(path) { return Err(ErrorType::CommandNotExecutableError(command_str)); }
+        return Ok(command.clone());
+    }
+    let path_var = envp.iter()
+        .find_map(|var| var.to_str().ok()?.strip_prefix("PATH="))
+        .unwrap_or(DEFAULT_PATH);
+    let mut not_executable = None;
+    for dir in path_var.split(':') {
+        let candidate = Path::new(if dir.is_empty() { "." } else { dir }).join(&command_str);
+        if is_executable(&candidate) {
+            log::debug!("Resolved {} to {}", command_str, candidate.display());
+            return CString::new(candidate.as_os_str().as_bytes()).map_err(ErrorType::CStringError);
+        } else if candidate.is_file() && not_executable.is_none() {
+            not_executable = Some(candidate.display().to_string());
+        }
+    }
+    match not_executable {
+        Some(path) => Err(ErrorType::CommandNotExecutableError(path)),
+        None => Err(ErrorType::CommandNotFoundError(command_str))
+    }
+}
+
+pub fn drop_capabilities(capabilities_drop: &[Cap]) -> Result<(), ErrorType> {
+    for cap in capabilities_drop.iter() {
+        bounding::ensure_dropped(*cap).map_err(|err| ErrorType::CapabilityError(err.into()))?;
+    }
+    let mut caps = CapState::get_current().map_err(|err| ErrorType::CapabilityError(err.into()))?;
+    caps.inheritable.drop_all(capabilities_drop.iter().copied());
+    caps.set_current().map_err(|err| ErrorType::CapabilityError(err.into()))?;
+    log::info!("Successfully dropped unwanted capabilities");
+    Ok(())
+}
+
+fn add_rule(ctx: &mut Context, rule: &SeccompRule) -> Result<(), ErrorType> {
+    if rule.conditions.is_empty() {
+        ctx.set_action_for_syscall(rule.action, rule.syscall).map_err(ErrorType::SyscallError)
+    } else {
+        ctx.set_rule_for_syscall(rule.action, rule.syscall, &rule.conditions).map_err(ErrorType::SyscallError)
+    }
 }
 
-pub fn restrict_syscalls() -> Result<(), ErrorType> {
-    let mut ctx = Context::init_with_action(Action::Allow).map_err(ErrorType::SyscallError)?;
-    for syscall in SYSCALLS_REFUSED.iter() { refuse_syscall(&mut ctx, syscall)?; }
-    for (syscall, ind, biteq) in SYSCALLS_CONDITIONALLY_REFUSED.iter() {
-        refuse_conditionally(&mut ctx, syscall, *ind, *biteq)?;
+pub fn restrict_syscalls(profile: &SeccompProfile) -> Result<(), ErrorType> {
+    let mut ctx = Context::init_with_action(profile.default_action).map_err(ErrorType::SyscallError)?;
+    // libseccomp refuses rules whose action is the default one
+    let default_action: u32 = profile.default_action.into();
+    for rule in profile.rules.iter().filter(|rule| u32::from(rule.action) != default_action) {
+        add_rule(&mut ctx, rule)?;
     }
     ctx.load().map_err(ErrorType::SyscallError)?;
     log::info!("Refused and filtered unwanted syscalls");
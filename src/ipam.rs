@@ -0,0 +1,116 @@
+use crate::childproc::ChildProcess;
+use crate::errors::ErrorType;
+use crate::network::Subnet;
+use crate::state::STATE_DIR;
+
+use nix::fcntl::{Flock, FlockArg};
+use nix::unistd::Pid;
+use serde::{Deserialize, Serialize};
+
+use std::fs::{create_dir_all, read_to_string, rename, write, File, OpenOptions};
+use std::net::Ipv4Addr;
+use std::path::PathBuf;
+
+// Shared by every rucker process, under a name no container ID can take
+const IPAM_DIR: &str = ".ipam";
+const LEASE_FILE: &str = "leases.json";
+const LOCK_FILE: &str = "lock";
+
+// An address given to a container, held for as long as its process lives
+#[derive(Debug, Serialize, Deserialize)]
+struct Lease {
+    id: String,
+    address: Ipv4Addr,
+    subnet: String,
+    pid: i32,
+    // See ContainerState::start_time
+    start_time: Option<u64>
+}
+
+impl Lease {
+    fn is_alive(&self) -> bool {
+        let child_proc = ChildProcess::of(Pid::from_raw(self.pid));
+        child_proc.is_alive() && (self.start_time.is_none() || child_proc.start_time() == self.start_time)
+    }
+}
+
+fn ipam_dir() -> PathBuf {
+    PathBuf::from(STATE_DIR).join(IPAM_DIR)
+}
+
+// The leases, read while holding the lock, which is released when dropped
+struct Leases {
+    _lock: Flock<File>,
+    leases: Vec<Lease>
+}
+
+impl Leases {
+    // Blocks until no other rucker holds the lock
+    fn lock() -> Result<Leases, ErrorType> {
+        let dir = ipam_dir();
+        create_dir_all(&dir).map_err(ErrorType::StateError)?;
+        let file = OpenOptions::new().write(true).create(true).truncate(false).open(dir.join(LOCK_FILE))
+            .map_err(ErrorType::StateError)?;
+        let lock = Flock::lock(file, FlockArg::LockExclusive).map_err(|(_, errno)| ErrorType::StateError(errno.into()))?;
+        let leases: Vec<Lease> = match read_to_string(dir.join(LEASE_FILE)) {
+            Ok(content) => serde_json::from_str(&content).map_err(ErrorType::JsonError)?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => return Err(ErrorType::StateError(err))
+        };
+        Ok(Leases { _lock: lock, leases })
+    }
+
+    // Forgets the leases of containers whose process is gone without releasing them,
+    // e.g. after rucker itself was killed
+    fn collect_garbage(&mut self) {
+        self.leases.retain(|lease| {
+            let alive = lease.is_alive();
+            if !alive { log::info!("Released address {} of container {}, its process is gone", lease.address, lease.id); }
+            alive
+        });
+    }
+
+    // Written to a temporary file first, like the container states
+    fn save(self) -> Result<(), ErrorType> {
+        let dir = ipam_dir();
+        let content = serde_json::to_string_pretty(&self.leases).map_err(ErrorType::JsonError)?;
+        let tmp_path = dir.join(format!(".{}.tmp", LEASE_FILE));
+        write(&tmp_path, content).map_err(ErrorType::StateError)?;
+        rename(&tmp_path, dir.join(LEASE_FILE)).map_err(ErrorType::StateError)
+    }
+}
+
+// Leases the requested address to the container, or the first one of the subnet no other
+// container holds
+pub fn allocate_address(id: &str, pid: Pid, subnet: &Subnet, requested: Option<Ipv4Addr>) -> Result<Ipv4Addr, ErrorType> {
+    let mut leases = Leases::lock()?;
+    leases.collect_garbage();
+    leases.leases.retain(|lease| lease.id != id);
+    let taken = |address: &Ipv4Addr| leases.leases.iter().find(|lease| lease.address == *address);
+    let address = match requested {
+        Some(address) => match taken(&address) {
+            Some(lease) => return Err(ErrorType::InvalidStateError(format!("address {} is already taken by container {}", address, lease.id))),
+            None => address
+        },
+        None => subnet.hosts().find(|address| taken(address).is_none())
+            .ok_or_else(|| ErrorType::InvalidStateError(format!("no address left in subnet {}", subnet)))?
+    };
+    leases.leases.push(Lease {
+        id: id.to_string(),
+        address,
+        subnet: subnet.to_string(),
+        pid: pid.as_raw(),
+        start_time: ChildProcess::of(pid).start_time()
+    });
+    leases.save()?;
+    log::debug!("Leased address {} to container {}", address, id);
+    Ok(address)
+}
+
+pub fn release_address(id: &str) -> Result<(), ErrorType> {
+    let mut leases = Leases::lock()?;
+    leases.leases.retain(|lease| lease.id != id);
+    leases.save()?;
+    log::debug!("Released the address of container {}", id);
+    Ok(())
+}
@@ -1,7 +1,130 @@
-use crate::errors::ErrorType;
+use crate::errors::{ErrorType, SetupFailure};
 
-use std::os::fd::{AsRawFd, OwnedFd};
-use nix::sys::socket::{socketpair, AddressFamily, SockType, SockFlag, MsgFlags, send, recv};
+use std::io::{IoSlice, IoSliceMut};
+use std::os::fd::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::ffi::OsStr;
+use std::path::PathBuf;
+use std::time::Duration;
+use nix::cmsg_space;
+use nix::errno::Errno;
+use nix::sys::socket::{socketpair, setsockopt, sockopt, recvmsg, sendmsg};
+use nix::sys::socket::{AddressFamily, SockType, SockFlag, MsgFlags, ControlMessage, ControlMessageOwned};
+use nix::sys::time::TimeVal;
+
+// Every message is a frame of a one byte tag and a little-endian u32 payload
+// length, followed by the payload; file descriptors travel as SCM_RIGHTS
+const HEADER_SIZE: usize = 5;
+const MAX_PAYLOAD_SIZE: usize = 64 * 1024;
+const MAX_FDS: usize = 16;
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+const TAG_USER_NAMESPACE: u8 = 0;
+const TAG_ID_MAPPED: u8 = 1;
+const TAG_SETUP_FAILURE: u8 = 2;
+const TAG_CONFIG: u8 = 3;
+const TAG_READY: u8 = 4;
+const TAG_CGROUP_JOINED: u8 = 5;
+
+#[derive(Debug)]
+pub enum Message {
+    // Child -> parent: whether the user namespace could be unshared
+    UserNamespace(bool),
+    // Parent -> child: whether the uid/gid maps have been written
+    IdMapped(bool),
+    // Child -> parent: the container setup failed, the child is exiting
+    SetupFailure(SetupFailure),
+    // Opaque configuration blob
+    #[allow(dead_code)]
+    Config(Vec<u8>),
+    // Child -> parent: setup is complete, execve comes next
+    Ready,
+    // Parent -> child: the child is in its cgroup, and may start setting itself up
+    CgroupJoined
+}
+
+impl Message {
+    fn tag(&self) -> u8 {
+        match self {
+            Message::UserNamespace(_) => TAG_USER_NAMESPACE,
+            Message::IdMapped(_) => TAG_ID_MAPPED,
+            Message::SetupFailure(_) => TAG_SETUP_FAILURE,
+            Message::Config(_) => TAG_CONFIG,
+            Message::Ready => TAG_READY,
+            Message::CgroupJoined => TAG_CGROUP_JOINED
+        }
+    }
+
+    fn encode_payload(&self) -> Vec<u8> {
+        match self {
+            Message::UserNamespace(boolean) | Message::IdMapped(boolean) => vec![(*boolean).into()],
+            Message::SetupFailure(failure) => {
+                let mut payload = Vec::new();
+                payload.extend_from_slice(&failure.exit_code.to_le_bytes());
+                payload.extend_from_slice(&(failure.errno as i32).to_le_bytes());
+                encode_bytes(&mut payload, failure.step.as_bytes());
+                encode_bytes(&mut payload, failure.path.as_ref().map(|path| path.as_os_str().as_bytes()).unwrap_or_default());
+                payload
+            },
+            Message::Config(blob) => blob.clone(),
+            Message::Ready | Message::CgroupJoined => Vec::new()
+        }
+    }
+
+    fn decode(tag: u8, payload: &[u8]) -> Result<Message, ErrorType> {
+        let mut decoder = Decoder { data: payload };
+        let message = match tag {
+            TAG_USER_NAMESPACE => Message::UserNamespace(decoder.boolean()?),
+            TAG_ID_MAPPED => Message::IdMapped(decoder.boolean()?),
+            TAG_SETUP_FAILURE => {
+                let exit_code = decoder.i32()?;
+                let errno = Errno::from_raw(decoder.i32()?);
+                let step = String::from_utf8_lossy(decoder.bytes()?).into_owned();
+                let path = decoder.bytes()?;
+                let path = if path.is_empty() { None } else { Some(PathBuf::from(OsStr::from_bytes(path))) };
+                Message::SetupFailure(SetupFailure { step, errno, path, exit_code })
+            },
+            TAG_CONFIG => Message::Config(decoder.take(payload.len())?.to_vec()),
+            TAG_READY => Message::Ready,
+            TAG_CGROUP_JOINED => Message::CgroupJoined,
+            _ => return Err(ErrorType::IpcProtocolError(format!("unknown message tag {}", tag)))
+        };
+        if !decoder.data.is_empty() {
+            return Err(ErrorType::IpcProtocolError(format!("{} trailing bytes after message", decoder.data.len())));
+        }
+        Ok(message)
+    }
+}
+
+fn encode_bytes(payload: &mut Vec<u8>, bytes: &[u8]) {
+    payload.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    payload.extend_from_slice(bytes);
+}
+
+struct Decoder<'a> {
+    data: &'a [u8]
+}
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], ErrorType> {
+        if self.data.len() < len {
+            return Err(ErrorType::IpcProtocolError(String::from("truncated message payload")));
+        }
+        let (head, tail) = self.data.split_at(len);
+        self.data = tail;
+        Ok(head)
+    }
+    fn boolean(&mut self) -> Result<bool, ErrorType> {
+        Ok(self.take(1)?[0] == 1)
+    }
+    fn i32(&mut self) -> Result<i32, ErrorType> {
+        Ok(i32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+    fn bytes(&mut self) -> Result<&'a [u8], ErrorType> {
+        let len = u32::from_le_bytes(self.take(4)?.try_into().unwrap());
+        self.take(len as usize)
+    }
+}
 
 pub fn create_socketpair() -> Result<(OwnedFd, OwnedFd), ErrorType> {
     socketpair(
@@ -12,14 +135,82 @@ pub fn create_socketpair() -> Result<(OwnedFd, OwnedFd), ErrorType> {
     ).map_err(ErrorType::SocketPairError)
 }
 
-pub fn send_boolean(fd: &OwnedFd, boolean: bool) -> Result<(), ErrorType> {
-    let data: [u8; 1] = [boolean.into()];
-    send(fd.as_raw_fd(), &data, MsgFlags::empty()).map_err(ErrorType::SocketSendError)?;
+// Bounds how long recv_message may block on this socket, None blocks forever
+pub fn set_recv_timeout(fd: &OwnedFd, timeout: Option<Duration>) -> Result<(), ErrorType> {
+    let timeout = timeout.unwrap_or(Duration::ZERO);
+    let timeval = TimeVal::new(timeout.as_secs() as _, timeout.subsec_micros() as _);
+    setsockopt(fd, sockopt::ReceiveTimeout, &timeval).map_err(ErrorType::SocketOptionError)
+}
+
+pub fn send_message(fd: &OwnedFd, message: &Message) -> Result<(), ErrorType> {
+    send_message_with_fds(fd, message, &[])
+}
+
+pub fn send_message_with_fds(fd: &OwnedFd, message: &Message, fds: &[BorrowedFd]) -> Result<(), ErrorType> {
+    let payload = message.encode_payload();
+    if payload.len() > MAX_PAYLOAD_SIZE {
+        return Err(ErrorType::IpcProtocolError(format!("message payload of {} bytes is too large", payload.len())));
+    }
+    if fds.len() > MAX_FDS {
+        return Err(ErrorType::IpcProtocolError(format!("cannot pass {} file descriptors at once", fds.len())));
+    }
+    let mut header = [message.tag(), 0, 0, 0, 0];
+    header[1..].copy_from_slice(&(payload.len() as u32).to_le_bytes());
+    let iov = [IoSlice::new(&header), IoSlice::new(&payload)];
+    let raw_fds: Vec<RawFd> = fds.iter().map(|fd| fd.as_raw_fd()).collect();
+    let cmsgs: Vec<ControlMessage> = if raw_fds.is_empty() { vec![] } else { vec![ControlMessage::ScmRights(&raw_fds)] };
+    log::debug!("Sending message {:?} with {} fds", message, fds.len());
+    sendmsg::<()>(fd.as_raw_fd(), &iov, &cmsgs, MsgFlags::MSG_NOSIGNAL, None).map_err(ErrorType::SocketSendError)?;
     Ok(())
 }
 
-pub fn recv_boolean(fd: &OwnedFd) -> Result<bool, ErrorType> {
-    let mut data: [u8; 1] = [0];
-    recv(fd.as_raw_fd(), &mut data, MsgFlags::empty()).map_err(ErrorType::SocketRecvError)?;
-    Ok(data[0] == 1)
+// Returns None once the other end of the socketpair has been closed
+pub fn recv_message_with_fds(fd: &OwnedFd) -> Result<Option<(Message, Vec<OwnedFd>)>, ErrorType> {
+    let mut buffer = vec![0u8; HEADER_SIZE + MAX_PAYLOAD_SIZE];
+    let mut cmsg_buffer = cmsg_space!([RawFd; MAX_FDS]);
+    let mut iov = [IoSliceMut::new(&mut buffer)];
+    let msg = recvmsg::<()>(fd.as_raw_fd(), &mut iov, Some(&mut cmsg_buffer), MsgFlags::MSG_CMSG_CLOEXEC)
+        .map_err(|err| ErrorType::SocketRecvError(if err == Errno::EAGAIN { Errno::ETIMEDOUT } else { err }))?;
+    let mut fds = Vec::new();
+    for cmsg in msg.cmsgs().map_err(ErrorType::SocketRecvError)? {
+        if let ControlMessageOwned::ScmRights(raw_fds) = cmsg {
+            fds.extend(raw_fds.into_iter().map(|raw_fd| unsafe { OwnedFd::from_raw_fd(raw_fd) }));
+        }
+    }
+    let (len, truncated) = (msg.bytes, msg.flags.contains(MsgFlags::MSG_TRUNC));
+    if len == 0 { return Ok(None); }
+    if truncated || len < HEADER_SIZE {
+        return Err(ErrorType::IpcProtocolError(format!("malformed frame of {} bytes", len)));
+    }
+    let payload_len = u32::from_le_bytes(buffer[1..HEADER_SIZE].try_into().unwrap()) as usize;
+    if payload_len != len - HEADER_SIZE {
+        return Err(ErrorType::IpcProtocolError(format!("frame announces {} bytes but carries {}", payload_len, len - HEADER_SIZE)));
+    }
+    let message = Message::decode(buffer[0], &buffer[HEADER_SIZE..len])?;
+    log::debug!("Received message {:?} with {} fds", message, fds.len());
+    Ok(Some((message, fds)))
+}
+
+// Receives the next message, turning a setup failure reported by the child
+// and a closed socket into errors
+pub fn recv_message(fd: &OwnedFd) -> Result<Message, ErrorType> {
+    match recv_message_with_fds(fd)? {
+        Some((Message::SetupFailure(failure), _)) => Err(ErrorType::ContainerSetupError(failure)),
+        Some((message, _)) => Ok(message),
+        None => Err(ErrorType::SocketRecvError(Errno::ECONNRESET))
+    }
+}
+
+// Waits until the child either reports a setup failure, or closes its end
+// of the socketpair (which is SOCK_CLOEXEC) by successfully calling execve
+pub fn recv_setup_result(fd: &OwnedFd) -> Result<(), ErrorType> {
+    match recv_message_with_fds(fd)? {
+        None => Ok(()),
+        Some((Message::SetupFailure(failure), _)) => Err(ErrorType::ContainerSetupError(failure)),
+        Some((message, _)) => Err(unexpected_message(message))
+    }
+}
+
+pub fn unexpected_message(message: Message) -> ErrorType {
+    ErrorType::IpcProtocolError(format!("unexpected message {:?}", message))
 }
@@ -0,0 +1,253 @@
+use crate::errors::ErrorType;
+use crate::childproc::{ChildProcess, forwarded_signals};
+use crate::state::{ContainerState, log_path};
+use crate::tty::{is_ready, read_available};
+use crate::LogsOptions;
+
+use nix::errno::Errno;
+use nix::poll::{poll, PollFd, PollFlags, PollTimeout};
+use nix::fcntl::OFlag;
+use nix::sys::signalfd::{SfdFlags, SignalFd};
+use nix::unistd::{dup2, pipe2};
+use serde::{Deserialize, Serialize};
+
+use std::fs::{rename, File, OpenOptions};
+use std::io::{stderr, stdout, BufRead, BufReader, Seek, SeekFrom, Write};
+use std::os::fd::{AsFd, AsRawFd, OwnedFd};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::thread::sleep;
+use std::time::{Duration, SystemTime};
+
+const BUFFER_SIZE: usize = 4096;
+const FOLLOW_INTERVAL: Duration = Duration::from_millis(200);
+
+#[derive(Clone, Copy)]
+pub struct LogConfig {
+    // The log file is rotated once it would grow past this size, in bytes
+    pub max_size: u64,
+    // Rotated files kept next to the current one, as container.log.1, container.log.2...
+    pub max_files: u32
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Stream {
+    Stdout,
+    Stderr
+}
+
+// A line of the log file
+#[derive(Serialize, Deserialize)]
+struct LogEntry {
+    // RFC 3339, with nanoseconds
+    time: String,
+    stream: Stream,
+    // The line as written by the container, with its newline if it had one
+    log: String
+}
+
+// Read and write ends of the stdout pipe, then of the stderr one
+pub fn open_log_pipes() -> Result<[OwnedFd; 4], ErrorType> {
+    let (stdout_read, stdout_write) = pipe2(OFlag::O_CLOEXEC).map_err(|err| ErrorType::LogError(err.into()))?;
+    let (stderr_read, stderr_write) = pipe2(OFlag::O_CLOEXEC).map_err(|err| ErrorType::LogError(err.into()))?;
+    Ok([stdout_read, stdout_write, stderr_read, stderr_write])
+}
+
+// Called in the child right before execve, see redirect_stdio for the pty
+pub fn redirect_output(stdout: &OwnedFd, stderr: &OwnedFd) -> Result<(), ErrorType> {
+    dup2(stdout.as_raw_fd(), 1).map_err(|err| ErrorType::LogError(err.into()))?;
+    dup2(stderr.as_raw_fd(), 2).map_err(|err| ErrorType::LogError(err.into()))?;
+    Ok(())
+}
+
+fn rotated_path(path: &Path, index: u32) -> PathBuf {
+    PathBuf::from(format!("{}.{}", path.display(), index))
+}
+
+// Writes the output of a container to its log file, one entry per line
+struct LogWriter {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    config: LogConfig,
+    // Incomplete lines, per stream
+    partial: [Vec<u8>; 2]
+}
+
+impl LogWriter {
+    fn open(path: PathBuf, config: LogConfig) -> Result<LogWriter, ErrorType> {
+        let file = OpenOptions::new().create(true).append(true).open(&path).map_err(ErrorType::LogError)?;
+        let size = file.metadata().map_err(ErrorType::LogError)?.len();
+        Ok(LogWriter { path, file, size, config, partial: [Vec::new(), Vec::new()] })
+    }
+
+    fn rotate(&mut self) -> Result<(), ErrorType> {
+        for index in (1..self.config.max_files).rev() {
+            let from = rotated_path(&self.path, index);
+            if from.exists() { rename(&from, rotated_path(&self.path, index + 1)).map_err(ErrorType::LogError)?; }
+        }
+        if self.config.max_files > 0 { rename(&self.path, rotated_path(&self.path, 1)).map_err(ErrorType::LogError)?; }
+        self.file = OpenOptions::new().create(true).write(true).truncate(true).open(&self.path).map_err(ErrorType::LogError)?;
+        self.size = 0;
+        Ok(())
+    }
+
+    fn write_entry(&mut self, stream: Stream, line: &[u8]) -> Result<(), ErrorType> {
+        let entry = LogEntry {
+            time: humantime::format_rfc3339_nanos(SystemTime::now()).to_string(),
+            stream,
+            log: String::from_utf8_lossy(line).into_owned()
+        };
+        let mut content = serde_json::to_string(&entry).map_err(ErrorType::JsonError)?;
+        content.push('\n');
+        if self.size > 0 && self.size + content.len() as u64 > self.config.max_size { self.rotate()?; }
+        self.file.write_all(content.as_bytes()).map_err(ErrorType::LogError)?;
+        self.size += content.len() as u64;
+        Ok(())
+    }
+
+    fn write(&mut self, stream: Stream, data: &[u8]) -> Result<(), ErrorType> {
+        let index = stream as usize;
+        self.partial[index].extend_from_slice(data);
+        while let Some(end) = self.partial[index].iter().position(|&byte| byte == b'\n') {
+            let line: Vec<u8> = self.partial[index].drain(..=end).collect();
+            self.write_entry(stream, &line)?;
+        }
+        // A line too long to wait for its end
+        if self.partial[index].len() >= BUFFER_SIZE {
+            let line = std::mem::take(&mut self.partial[index]);
+            self.write_entry(stream, &line)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self, stream: Stream) -> Result<(), ErrorType> {
+        let line = std::mem::take(&mut self.partial[stream as usize]);
+        if !line.is_empty() { self.write_entry(stream, &line)?; }
+        Ok(())
+    }
+}
+
+impl ChildProcess {
+    // Like wait_forwarding, while writing what the child prints on its stdout and stderr pipes to its log file
+    pub fn wait_logging(&mut self, pipes: [OwnedFd; 2], path: PathBuf, config: LogConfig) -> Result<i32, ErrorType> {
+        log::debug!("Waiting for child_proc (pid {}) to finish, logging to {}", self.pid, path.display());
+        let mut writer = LogWriter::open(path, config)?;
+        let signal_fd = SignalFd::with_flags(&forwarded_signals(), SfdFlags::SFD_CLOEXEC).map_err(ErrorType::SignalError)?;
+        let streams = [Stream::Stdout, Stream::Stderr];
+        let mut open = [true, true];
+        let mut exit_code = None;
+        let mut buffer = [0; BUFFER_SIZE];
+        // Once the child exited, what is left in the pipes is read without blocking
+        while open.iter().any(|&open| open) {
+            let mut fds = vec![PollFd::new(signal_fd.as_fd(), PollFlags::POLLIN)];
+            let polled: Vec<usize> = (0..2).filter(|&index| open[index]).collect();
+            for &index in polled.iter() { fds.push(PollFd::new(pipes[index].as_fd(), PollFlags::POLLIN)); }
+            let timeout = if exit_code.is_some() { PollTimeout::ZERO } else { PollTimeout::NONE };
+            let ready = match poll(&mut fds, timeout) {
+                Err(Errno::EINTR) => continue,
+                result => result.map_err(|err| ErrorType::LogError(err.into()))?
+            };
+            if ready == 0 { break; }
+            let signal_ready = is_ready(fds.first());
+            let ready: Vec<usize> = polled.iter().enumerate().filter(|(position, _)| is_ready(fds.get(position + 1))).map(|(_, &index)| index).collect();
+            drop(fds);
+            for index in ready {
+                match read_available(pipes[index].as_fd(), &mut buffer).map_err(|err| ErrorType::LogError(err.into()))? {
+                    Some(size) => writer.write(streams[index], &buffer[..size])?,
+                    None => open[index] = false
+                }
+            }
+            if signal_ready && exit_code.is_none() { exit_code = self.handle_signal(&signal_fd)?; }
+        }
+        for stream in streams { writer.flush(stream)?; }
+        match exit_code {
+            Some(code) => Ok(code),
+            // Both pipes were closed before the child exited
+            None => self.wait_forwarding()
+        }
+    }
+}
+
+// Accepts a duration like "10m" back from now, or an RFC 3339 time
+fn parse_since(since: &str) -> Result<SystemTime, ErrorType> {
+    if let Ok(duration) = humantime::parse_duration(since) {
+        return Ok(SystemTime::now() - duration);
+    }
+    humantime::parse_rfc3339_weak(since)
+        .map_err(|_| ErrorType::InvalidArgumentError(format!("invalid time {:?}, expected e.g. 10m or 2024-01-01T00:00:00Z", since)))
+}
+
+fn print_entry(entry: &LogEntry) -> Result<(), ErrorType> {
+    match entry.stream {
+        Stream::Stdout => stdout().lock().write_all(entry.log.as_bytes()),
+        Stream::Stderr => stderr().lock().write_all(entry.log.as_bytes())
+    }.map_err(ErrorType::LogError)
+}
+
+// Reads the entries from the current position of a log file, up to its end
+fn read_entries(reader: &mut BufReader<File>, since: Option<SystemTime>) -> Result<Vec<LogEntry>, ErrorType> {
+    let mut entries = Vec::new();
+    let mut line = String::new();
+    loop {
+        line.clear();
+        let position = reader.stream_position().map_err(ErrorType::LogError)?;
+        if reader.read_line(&mut line).map_err(ErrorType::LogError)? == 0 { break; }
+        // An entry still being written, read again on the next pass
+        if !line.ends_with('\n') {
+            reader.seek(SeekFrom::Start(position)).map_err(ErrorType::LogError)?;
+            break;
+        }
+        let entry: LogEntry = serde_json::from_str(&line).map_err(ErrorType::JsonError)?;
+        let is_recent = since.is_none_or(|since| humantime::parse_rfc3339(&entry.time).is_ok_and(|time| time >= since));
+        if is_recent { entries.push(entry); }
+    }
+    Ok(entries)
+}
+
+// Whether the container may still write to its log
+fn is_logging(id: &str) -> bool {
+    ContainerState::load(id).is_ok_and(|state| state.exit_code.is_none() && state.is_alive())
+}
+
+pub fn logs(opt: LogsOptions) -> Result<i32, ErrorType> {
+    let state = ContainerState::load(&opt.id)?;
+    let path = log_path(&state.id);
+    if !path.exists() {
+        return Err(ErrorType::InvalidStateError(format!("container {} has no logs, only detached containers do", opt.id)));
+    }
+    let since = opt.since.as_deref().map(parse_since).transpose()?;
+
+    // Rotated files first, oldest first
+    let mut entries = Vec::new();
+    let mut index = 1;
+    while rotated_path(&path, index).exists() { index += 1; }
+    for index in (1..index).rev() {
+        let file = File::open(rotated_path(&path, index)).map_err(ErrorType::LogError)?;
+        entries.extend(read_entries(&mut BufReader::new(file), since)?);
+    }
+    let mut reader = BufReader::new(File::open(&path).map_err(ErrorType::LogError)?);
+    entries.extend(read_entries(&mut reader, since)?);
+    let skipped = opt.tail.map_or(0, |tail| entries.len().saturating_sub(tail));
+    for entry in entries.iter().skip(skipped) { print_entry(entry)?; }
+    if !opt.follow { return Ok(0); }
+
+    let mut inode = reader.get_ref().metadata().map_err(ErrorType::LogError)?.ino();
+    loop {
+        // Checked before reading, so that the last entries are not missed
+        let logging = is_logging(&state.id);
+        for entry in read_entries(&mut reader, since)? { print_entry(&entry)?; }
+        if !logging { return Ok(0); }
+        // Once rotated, the rest of the old file is read before the new one, from its start
+        if let Ok(metadata) = std::fs::metadata(&path) {
+            if metadata.ino() != inode {
+                for entry in read_entries(&mut reader, since)? { print_entry(&entry)?; }
+                reader = BufReader::new(File::open(&path).map_err(ErrorType::LogError)?);
+                inode = metadata.ino();
+                continue;
+            }
+        }
+        sleep(FOLLOW_INTERVAL);
+    }
+}
@@ -1,5 +1,8 @@
+
 use container::run;
-use errors::ErrorType;
+use errors::{ErrorType, EXIT_SETUP_FAILURE};
+use config::DEFAULT_CGROUP_PARENT;
+use network::{DEFAULT_BRIDGE, DEFAULT_NETWORK, DEFAULT_SUBNET};
 
 use structopt::StructOpt;
 use users::get_current_uid;
@@ -9,6 +12,7 @@ use std::path::PathBuf;
 
 mod utils;
 mod errors;
+mod environ;
 mod container;
 mod childproc;
 mod internal;
@@ -17,75 +21,399 @@ mod namespace;
 mod capabilities;
 mod syscalls;
 mod cgroup;
+mod cgroup_v2;
+mod config;
+mod state;
+mod oci;
+mod ps;
+mod exec;
+mod init;
+mod tty;
+mod logs;
+mod rlimits;
+mod stats;
+mod oom;
+mod netlink;
+mod nftables;
+mod network;
+mod ipam;
+mod ports;
+mod proxy;
+mod etc_files;
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "rucker", about = "Linux container written in Rust")]
 pub struct CLI {
-    // Prints debug information
-    #[structopt(long, short, global = true)]
+    // Prints debug information, wherever it is given
+    #[structopt(long, global = true)]
     debug: bool,
+    // The short form of --debug, only before the command: `run -d` detaches the container
+    #[structopt(short = "d")]
+    debug_short: bool,
     #[structopt(subcommand)]
     command: Command
 }
 
 #[derive(Debug, StructOpt)]
+#[allow(clippy::large_enum_variant)]
 pub enum Command {
     #[structopt(name = "run", about = "Run a container from a mount directory")]
-    Run(RunOptions)
+    Run(RunOptions),
+    #[structopt(name = "create", about = "Create a container from an OCI bundle, without starting it")]
+    Create(CreateOptions),
+    #[structopt(name = "start", about = "Start a created container")]
+    Start(IdOptions),
+    #[structopt(name = "state", about = "Print the OCI state of a container")]
+    State(IdOptions),
+    #[structopt(name = "kill", about = "Send a signal to the process of a container")]
+    Kill(KillOptions),
+    #[structopt(name = "stop", about = "Stop a container with SIGTERM, then SIGKILL after a timeout")]
+    Stop(StopOptions),
+    #[structopt(name = "delete", about = "Delete a stopped container")]
+    Delete(DeleteOptions),
+    #[structopt(name = "ps", about = "List containers")]
+    Ps,
+    #[structopt(name = "inspect", about = "Print what rucker knows about a container")]
+    Inspect(IdOptions),
+    #[structopt(name = "logs", about = "Print the output of a detached container")]
+    Logs(LogsOptions),
+    #[structopt(name = "stats", about = "Print the resource usage of running containers")]
+    Stats(StatsOptions),
+    #[structopt(name = "exec", about = "Run a command in a running container")]
+    Exec(ExecOptions)
 }
 
 #[derive(Debug, StructOpt)]
 pub struct RunOptions {
-    // Command to execute inside the container
-    #[structopt(short="c", long)]
-    exec_command: String,
+    // Command line to execute inside the container, given after `--`
+    #[structopt(last = true, required = true)]
+    pub command: Vec<String>,
+    // Set environment variables inside the container (KEY=VAL, or KEY to pass it from the host)
+    #[structopt(short, long="env")]
+    pub envs: Vec<String>,
+    // Read environment variables from a file of KEY=VAL lines
+    #[structopt(long, parse(from_os_str))]
+    pub env_file: Vec<PathBuf>,
     // Root directory inside the container to mount
     #[structopt(short, long, parse(from_os_str))]
     pub mount_dir: PathBuf,
     // Mount more directories inside the container
     #[structopt(short, long="additional_mount_dirs")]
     pub addmntpts: Vec<String>,
+    // Working directory inside the container, created if missing
+    #[structopt(short, long, parse(from_os_str), default_value="/")]
+    pub workdir: PathBuf,
     // User ID to create inside the container
     #[structopt(short, long, default_value="0")]
-    pub uid: u32
+    pub uid: u32,
+    // Run the container in the background and print its ID
+    #[structopt(short, long)]
+    pub detach: bool,
+    // Run the command under a minimal init that forwards signals and reaps zombies
+    #[structopt(long)]
+    pub init: bool,
+    // Set a resource limit of the command, as name=soft:hard or name=limit, e.g. nofile=1024:4096
+    #[structopt(long = "ulimit", number_of_values = 1)]
+    pub ulimits: Vec<String>,
+    // Allocate a pseudo-terminal for the command
+    #[structopt(short, long)]
+    pub tty: bool,
+    // Forward stdin to the pseudo-terminal of the command
+    #[structopt(short, long)]
+    pub interactive: bool,
+    // Size in bytes past which the log file of a detached container is rotated
+    #[structopt(long, default_value="10485760")]
+    pub log_max_size: u64,
+    // Number of rotated log files kept for a detached container
+    #[structopt(long, default_value="1")]
+    pub log_max_files: u32,
+    // Cgroup the container's cgroup is created under, e.g. /rucker.slice
+    #[structopt(long, default_value = DEFAULT_CGROUP_PARENT)]
+    pub cgroup_parent: String,
+    // Network of the container: none, loopback, host, or bridge for loopback plus a veth pair to the bridge
+    #[structopt(long, default_value = DEFAULT_NETWORK)]
+    pub network: String,
+    // Bridge the container is connected to, created if missing
+    #[structopt(long, default_value = DEFAULT_BRIDGE)]
+    pub bridge: String,
+    // Subnet of the bridge the container is given an address from, e.g. 10.88.0.0/16
+    #[structopt(long, default_value = DEFAULT_SUBNET)]
+    pub subnet: String,
+    // Address of the container on the bridge, one of the subnet's left free otherwise
+    #[structopt(long)]
+    pub ip: Option<String>,
+    // Hostname of the container, its ID by default
+    #[structopt(long)]
+    pub hostname: Option<String>,
+    // Add a line to the container's /etc/hosts, as name:address
+    #[structopt(long = "add-host", number_of_values = 1)]
+    pub add_hosts: Vec<String>,
+    // Nameserver of the container's /etc/resolv.conf, instead of the host's
+    #[structopt(long, number_of_values = 1)]
+    pub dns: Vec<String>,
+    // Search domain of the container's /etc/resolv.conf, instead of the host's
+    #[structopt(long = "dns-search", number_of_values = 1)]
+    pub dns_search: Vec<String>,
+    // Publish a port of the container on the host, as hostPort:containerPort[/tcp|udp], e.g. 8080:80
+    #[structopt(short, long = "publish", number_of_values = 1)]
+    pub publish: Vec<String>,
+    // Boxed, as it would make RunOptions much larger than the other commands' options
+    #[structopt(flatten)]
+    pub resources: Box<ResourceOptions>
+}
+
+// Limits of the container's cgroup, the unset ones keeping rucker's defaults
+#[derive(Debug, StructOpt)]
+pub struct ResourceOptions {
+    // Memory limit, e.g. 512m or 2g
+    #[structopt(long)]
+    pub memory: Option<String>,
+    // Limit of memory plus swap, -1 for unlimited swap
+    #[structopt(long, allow_hyphen_values = true)]
+    pub memory_swap: Option<String>,
+    // Soft memory limit, enforced when memory runs short
+    #[structopt(long)]
+    pub memory_reservation: Option<String>,
+    // Number of CPUs the container may use, e.g. 1.5
+    #[structopt(long)]
+    pub cpus: Option<f64>,
+    // Relative CPU weight against other containers
+    #[structopt(long)]
+    pub cpu_shares: Option<u64>,
+    // CPUs the container may run on, e.g. 0-3,6
+    #[structopt(long)]
+    pub cpuset_cpus: Option<String>,
+    // Memory nodes the container may allocate from, e.g. 0-1
+    #[structopt(long)]
+    pub cpuset_mems: Option<String>,
+    // Maximum number of processes, negative for no limit
+    #[structopt(long, allow_hyphen_values = true)]
+    pub pids_limit: Option<i64>,
+    // Relative block I/O weight, between 10 and 1000
+    #[structopt(long)]
+    pub blkio_weight: Option<u16>,
+    // Limit the read rate from a device, e.g. /dev/sda:1m
+    #[structopt(long)]
+    pub device_read_bps: Vec<String>,
+    // Limit the write rate to a device, e.g. /dev/sda:1m
+    #[structopt(long)]
+    pub device_write_bps: Vec<String>,
+    // Limit the read operations per second from a device, e.g. /dev/sda:1000
+    #[structopt(long)]
+    pub device_read_iops: Vec<String>,
+    // Limit the write operations per second to a device, e.g. /dev/sda:1000
+    #[structopt(long)]
+    pub device_write_iops: Vec<String>
+}
+
+#[derive(Debug, StructOpt)]
+pub struct LogsOptions {
+    // ID of the container
+    pub id: String,
+    // Keep printing new output until the container exits
+    #[structopt(short, long)]
+    pub follow: bool,
+    // Only print output since a time (RFC 3339) or for a duration back from now (e.g. 10m)
+    #[structopt(long)]
+    pub since: Option<String>,
+    // Only print the last N lines
+    #[structopt(long)]
+    pub tail: Option<usize>
+}
+
+#[derive(Debug, StructOpt)]
+pub struct ExecOptions {
+    // ID of the container
+    pub id: String,
+    // Command line to execute inside the container, given after `--`
+    #[structopt(last = true, required = true)]
+    pub command: Vec<String>,
+    // Set environment variables for the command (KEY=VAL, or KEY to pass it from the host)
+    #[structopt(short, long="env")]
+    pub envs: Vec<String>,
+    // Read environment variables from a file of KEY=VAL lines
+    #[structopt(long, parse(from_os_str))]
+    pub env_file: Vec<PathBuf>,
+    // Working directory inside the container, defaults to the one of the container
+    #[structopt(short, long, parse(from_os_str))]
+    pub workdir: Option<PathBuf>
+}
+
+#[derive(Debug, StructOpt)]
+pub struct CreateOptions {
+    // ID of the new container
+    pub id: String,
+    // Path to the OCI bundle, containing config.json and the root filesystem
+    #[structopt(short, long, parse(from_os_str), default_value=".")]
+    pub bundle: PathBuf,
+    // Write the pid of the container process to this file
+    #[structopt(long, parse(from_os_str))]
+    pub pid_file: Option<PathBuf>
+}
+
+#[derive(Debug, StructOpt)]
+pub struct StatsOptions {
+    // IDs of the containers, every running one if none is given
+    pub ids: Vec<String>,
+    // Print a single reading instead of refreshing every second
+    #[structopt(long)]
+    pub no_stream: bool,
+    // Print a JSON object per container and reading instead of a table
+    #[structopt(long)]
+    pub json: bool
+}
+
+#[derive(Debug, StructOpt)]
+pub struct IdOptions {
+    // ID of the container
+    pub id: String
+}
+
+#[derive(Debug, StructOpt)]
+pub struct KillOptions {
+    // ID of the container
+    pub id: String,
+    // Signal to send, by name or number
+    #[structopt(default_value="SIGTERM")]
+    pub signal: String,
+    // Send the signal to every process of the container, not only to its init
+    #[structopt(short, long)]
+    pub all: bool
+}
+
+#[derive(Debug, StructOpt)]
+pub struct StopOptions {
+    // ID of the container
+    pub id: String,
+    // Seconds to wait after SIGTERM before killing the container
+    #[structopt(short, long, default_value="10")]
+    pub time: u64
+}
+
+#[derive(Debug, StructOpt)]
+pub struct DeleteOptions {
+    // ID of the container
+    pub id: String,
+    // Kill the container first if it is still running
+    #[structopt(short, long)]
+    pub force: bool
+}
+
+fn log_error(err_type: ErrorType) {
+    if let ErrorType::CStringError(err) = err_type {
+        log::error!("Error converting String to CString, check the command and environment: {:?}", err);
+    } else if let ErrorType::SocketPairError(err) = err_type {
+        log::error!("Failed to create the socketpair: {:?}", err);
+    } else if let ErrorType::ChildProcessError(err) = err_type {
+        log::error!("Failed to setup child process: {:?}", err);
+    } else if let ErrorType::WaitingError(err) = err_type {
+        log::error!("Error while waiting for child process to finish: {:?}", err);
+    } else if let ErrorType::FileError(err) = err_type {
+        log::error!("File error: {:?}", err);
+    } else if let ErrorType::DirectoryError(err) = err_type {
+        log::error!("Directory error: {:?}", err);
+    } else if let ErrorType::SocketSendError(err) = err_type {
+        log::error!("Error while communicating with child process: Failed to send via socket: {:?}", err);
+    } else if let ErrorType::SocketRecvError(err) = err_type {
+        log::error!("Error while communicating with child process: Failed to recv via socket: {:?}", err);
+    } else if let ErrorType::SocketOptionError(err) = err_type {
+        log::error!("Error while communicating with child process: Failed to set socket option: {:?}", err);
+    } else if let ErrorType::IpcProtocolError(err) = err_type {
+        log::error!("Error while communicating with child process: {}", err);
+    } else if let ErrorType::CgroupError(err) = err_type {
+        log::error!("Failed to restrict resourses for child process: {:?}", err);
+    } else if let ErrorType::CgroupFileError(err, path) = err_type {
+        log::error!("Failed to manage cgroup: {:?} ({})", err, path.display());
+    } else if let ErrorType::NetlinkError(err, request) = err_type {
+        log::error!("Failed to set up the container network, could not {}: {:?}", request, err);
+    } else if let ErrorType::ProxyError(err, port) = err_type {
+        log::error!("Failed to proxy port {}: {:?}", port, err);
+    } else if let ErrorType::RlimitError(err) = err_type {
+        log::error!("Failed to limit resources for child process: {:?}", err);
+    } else if let ErrorType::EnvFileError(err) = err_type {
+        log::error!("Failed to read environment file: {:?}", err);
+    } else if let ErrorType::ContainerSetupError(failure) = err_type {
+        let path = failure.path.map(|path| format!(" ({})", path.display())).unwrap_or_default();
+        log::error!("Container setup failed during {}{}: {}", failure.step, path, failure.errno.desc());
+    } else if let ErrorType::SignalError(err) = err_type {
+        log::error!("Failed to signal the container process: {:?}", err);
+    } else if let ErrorType::ExecFifoError(err) = err_type {
+        log::error!("Failed to use the exec fifo of the container: {:?}", err);
+    } else if let ErrorType::StateError(err) = err_type {
+        log::error!("Failed to access the container state: {:?}", err);
+    } else if let ErrorType::JsonError(err) = err_type {
+        log::error!("Invalid JSON: {}", err);
+    } else if let ErrorType::SpecError(err) = err_type {
+        log::error!("Invalid OCI runtime spec: {}", err);
+    } else if let ErrorType::InvalidArgumentError(err) = err_type {
+        log::error!("Invalid argument: {}", err);
+    } else if let ErrorType::InvalidStateError(err) = err_type {
+        log::error!("Invalid container state: {}", err);
+    } else if let ErrorType::ContainerNotFoundError(id) = err_type {
+        log::error!("No such container: {}", id);
+    } else if let ErrorType::ContainerExistsError(id) = err_type {
+        log::error!("A container with ID {} already exists", id);
+    } else if let ErrorType::NamespaceError(err, path) = err_type {
+        log::error!("Failed to join namespace {}: {:?}", path.display(), err);
+    } else if let ErrorType::ChDirError(err, path) = err_type {
+        log::error!("Failed to change directory to {}: {:?}", path.display(), err);
+    } else if let ErrorType::CommandNotFoundError(command) = err_type {
+        log::error!("Command not found in the container: {}", command);
+    } else if let ErrorType::CommandNotExecutableError(command) = err_type {
+        log::error!("Command is not executable in the container: {}", command);
+    } else if let ErrorType::UserSysError(err) = err_type {
+        log::error!("Failed to switch uid: {:?}", err);
+    } else if let ErrorType::CapabilityError(err) = err_type {
+        log::error!("Failed to restrict capabilities: {:?}", err);
+    } else if let ErrorType::SyscallError(err) = err_type {
+        log::error!("Failed to restrict syscalls: {:?}", err);
+    } else if let ErrorType::ExecveError(err) = err_type {
+        log::error!("Failed to perform execve: {:?}", err);
+    } else if let ErrorType::DetachError(err) = err_type {
+        log::error!("Failed to run the container in the background: {:?}", err);
+    } else if let ErrorType::TtyError(err) = err_type {
+        log::error!("Terminal error: {:?}", err);
+    } else if let ErrorType::LogError(err) = err_type {
+        log::error!("Failed to access the container logs: {:?}", err);
+    } else {
+        log::error!("{:?}", err_type);
+    }
 }
 
 fn main() {
     let args = CLI::from_args();
     env_logger::Builder::from_default_env()
         .format_timestamp_secs()
-        .filter(None, if args.debug { log::LevelFilter::Debug } else { log::LevelFilter::Info })
+        .filter(None, if args.debug || args.debug_short { log::LevelFilter::Debug } else { log::LevelFilter::Info })
         .init();
     if get_current_uid() != 0 {
         log::error!("You need root privileges to run this program");
-        process::exit(0);
+        process::exit(EXIT_SETUP_FAILURE);
     }
-    match args.command {
-        Command::Run(opt) => {
-            match run(opt.exec_command, opt.mount_dir, opt.addmntpts, opt.uid) {
-                Ok(()) => log::info!("All done"),
-                Err(err_type) => {
-                    if let ErrorType::CStringError(err) = err_type {
-                        log::error!("Error converting String to CString, check the <exec_command>: {:?}", err);
-                    } else if let ErrorType::SocketPairError(err) = err_type {
-                        log::error!("Failed to create the socketpair: {:?}", err);
-                    } else if let ErrorType::ChildProcessError(err) = err_type {
-                        log::error!("Failed to setup child process: {:?}", err);
-                    } else if let ErrorType::WaitingError(err) = err_type {
-                        log::error!("Error while waiting for child process to finish: {:?}", err);
-                    } else if let ErrorType::FileError(err) = err_type {
-                        log::error!("Error while mapping UID/GID for child process: {:?}", err);
-                    } else if let ErrorType::SocketSendError(err) = err_type {
-                        log::error!("Error while communicating with child process: Failed to send via socket: {:?}", err);
-                    } else if let ErrorType::SocketRecvError(err) = err_type {
-                        log::error!("Error while communicating with child process: Failed to recv via socket: {:?}", err);
-                    } else if let ErrorType::CgroupError(err) = err_type {
-                        log::error!("Failed to restrict resourses for child process: {:?}", err);
-                    } else if let ErrorType::RlimitError(err) = err_type {
-                        log::error!("Failed to limit resources for child process: {:?}", err);
-                    }
-                }
-            };
+    let result = match args.command {
+        Command::Run(opt) => run(opt),
+        Command::Create(opt) => oci::create(opt),
+        Command::Start(opt) => oci::start(opt.id),
+        Command::State(opt) => oci::state(opt.id),
+        Command::Kill(opt) => oci::kill(opt),
+        Command::Stop(opt) => oci::stop(opt),
+        Command::Delete(opt) => oci::delete(opt),
+        Command::Ps => ps::ps(),
+        Command::Inspect(opt) => ps::inspect(opt.id),
+        Command::Logs(opt) => logs::logs(opt),
+        Command::Stats(opt) => stats::stats(opt),
+        Command::Exec(opt) => exec::exec(opt)
+    };
+    let exit_code = match result {
+        Ok(exit_code) => {
+            log::info!("All done, exiting with status {}", exit_code);
+            exit_code
+        },
+        Err(err_type) => {
+            let exit_code = err_type.exit_code();
+            log_error(err_type);
+            exit_code
         }
-    }
+    };
+    process::exit(exit_code);
 }
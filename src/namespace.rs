@@ -1,44 +1,57 @@
 use crate::errors::ErrorType;
 use crate::container::Container;
-use crate::ipc::{send_boolean, recv_boolean};
+use crate::config::IdMapping;
+use crate::ipc::{Message, send_message, recv_message, set_recv_timeout, unexpected_message, HANDSHAKE_TIMEOUT};
 
 use nix::sched::{unshare, CloneFlags};
 use nix::unistd::{Gid, Uid};
 use nix::unistd::{setgroups, setresuid, setresgid};
-use nix::unistd::close;
-
-use std::os::fd::AsRawFd;
 use std::fs::File;
 use std::io::Write;
 
-const USERNS_OFFSET: u64 = 10000;
-const USERNS_COUNT: u64 = 2000;
+fn write_id_map(path: &str, mappings: &[IdMapping]) -> Result<(), ErrorType> {
+    let content: String = mappings.iter()
+        .map(|m| format!("{} {} {}\n", m.container_id, m.host_id, m.size))
+        .collect();
+    let mut map = File::create(path).map_err(ErrorType::FileError)?;
+    map.write_all(content.as_bytes()).map_err(ErrorType::FileError)?;
+    Ok(())
+}
+
+pub fn switch_user(uid: u32, gid: u32) -> Result<(), ErrorType> {
+    let (uid, gid) = (Uid::from_raw(uid), Gid::from_raw(gid));
+    setgroups(&[gid]).map_err(ErrorType::UserSysError)?;
+    setresgid(gid, gid, gid).map_err(ErrorType::UserSysError)?;
+    setresuid(uid, uid, uid).map_err(ErrorType::UserSysError)?;
+    log::info!("Successfully switched to {}:{}", uid, gid);
+    Ok(())
+}
 
 impl Container {
     pub fn setup_user_namespace(&mut self) -> Result<(), ErrorType> {
-        if let Err(err) = unshare(CloneFlags::CLONE_NEWUSER) {
+        set_recv_timeout(self.child_socket(), Some(HANDSHAKE_TIMEOUT))?;
+        if !self.config.user_namespace {
+            log::debug!("No user namespace requested");
+            send_message(self.child_socket(), &Message::UserNamespace(false))?;
+        } else if let Err(err) = unshare(CloneFlags::CLONE_NEWUSER) {
             log::warn!("Failed to setup user namespace, maybe not supported: {:?}", err);
-            send_boolean(&self.socket_pair.1, false)?;
+            send_message(self.child_socket(), &Message::UserNamespace(false))?;
         } else {
             log::info!("Successfully set up user namespace");
-            send_boolean(&self.socket_pair.1, true)?;
+            send_message(self.child_socket(), &Message::UserNamespace(true))?;
         }
-        let is_mapped = recv_boolean(&self.socket_pair.1)?;
+        let is_mapped = match recv_message(self.child_socket())? {
+            Message::IdMapped(is_mapped) => is_mapped,
+            message => return Err(unexpected_message(message))
+        };
         log::debug!("Parent process acknowledged, is_mapped: {}", is_mapped);
-        close(self.socket_pair.1.as_raw_fd()).map_err(ErrorType::SocketCloseError)?;
-        let (uid, gid) = (Uid::from_raw(self.uid), Gid::from_raw(self.uid));
-        setgroups(&[gid]).map_err(ErrorType::UserSysError)?;
-        setresgid(gid, gid, gid).map_err(ErrorType::UserSysError)?;
-        setresuid(uid, uid, uid).map_err(ErrorType::UserSysError)?;
-        log::info!("Successfully switched to {}:{}", uid, gid);
         Ok(())
     }
 
     pub fn map_child_uid(&mut self) -> Result<(), ErrorType> {
-        let mut uid_map = File::create(format!("/proc/{}/{}", self.child_proc.as_ref().unwrap().pid.as_raw(), "uid_map")).map_err(ErrorType::FileError)?;
-        uid_map.write_all(format!("0 {} {}", USERNS_OFFSET, USERNS_COUNT).as_bytes()).map_err(ErrorType::FileError)?;
-        let mut gid_map = File::create(format!("/proc/{}/{}", self.child_proc.as_ref().unwrap().pid.as_raw(), "gid_map")).map_err(ErrorType::FileError)?;
-        gid_map.write_all(format!("0 {} {}", USERNS_OFFSET, USERNS_COUNT).as_bytes()).map_err(ErrorType::FileError)?;
+        let pid = self.child_proc.as_ref().unwrap().pid.as_raw();
+        write_id_map(&format!("/proc/{}/uid_map", pid), &self.config.uid_mappings)?;
+        write_id_map(&format!("/proc/{}/gid_map", pid), &self.config.gid_mappings)?;
         Ok(())
     }
 }
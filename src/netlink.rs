@@ -0,0 +1,172 @@
+use crate::errors::ErrorType;
+
+use netlink_packet_core::{NetlinkMessage, NetlinkPayload, NLM_F_ACK, NLM_F_CREATE, NLM_F_EXCL, NLM_F_REQUEST};
+use netlink_packet_route::{AddressMessage, LinkMessage, RouteMessage, RtnlMessage};
+use netlink_packet_route::{AF_INET, IFF_UP, RTN_UNICAST, RTPROT_STATIC, RT_SCOPE_UNIVERSE, RT_TABLE_MAIN};
+use netlink_packet_route::nlas::address::Nla as AddressNla;
+use netlink_packet_route::nlas::link::{Info, InfoData, InfoKind, Nla as LinkNla, VethInfo};
+use netlink_packet_route::nlas::route::Nla as RouteNla;
+use netlink_sys::protocols::NETLINK_ROUTE;
+use netlink_sys::{Socket, SocketAddr};
+
+use std::io::{Error, ErrorKind};
+use std::net::Ipv4Addr;
+
+fn netlink_error(what: &str) -> impl Fn(Error) -> ErrorType + '_ {
+    move |err| ErrorType::NetlinkError(err, what.to_string())
+}
+
+// A netlink socket of the given protocol connected to the kernel, in the network namespace of
+// the calling thread
+pub fn open_socket(protocol: isize) -> Result<Socket, ErrorType> {
+    let error = netlink_error("open socket");
+    let mut socket = Socket::new(protocol).map_err(&error)?;
+    socket.bind_auto().map_err(&error)?;
+    socket.connect(&SocketAddr::new(0, 0)).map_err(&error)?;
+    Ok(socket)
+}
+
+// The messages of a buffer received from a netlink socket, as (type, flags, sequence, payload)
+pub fn split_messages(buffer: &[u8]) -> Vec<(u16, u16, u32, &[u8])> {
+    let mut messages = Vec::new();
+    let mut offset = 0;
+    while buffer.len() >= offset + 16 {
+        let field = |at: usize, size: usize| &buffer[offset + at..offset + at + size];
+        let length = u32::from_ne_bytes(field(0, 4).try_into().unwrap()) as usize;
+        if length < 16 || offset + length > buffer.len() { break; }
+        let kind = u16::from_ne_bytes(field(4, 2).try_into().unwrap());
+        let flags = u16::from_ne_bytes(field(6, 2).try_into().unwrap());
+        let sequence = u32::from_ne_bytes(field(8, 4).try_into().unwrap());
+        messages.push((kind, flags, sequence, &buffer[offset + 16..offset + length]));
+        // Messages are aligned on 4 bytes
+        offset += (length + 3) & !3;
+    }
+    messages
+}
+
+// A synchronous rtnetlink client, managing the links, addresses and routes of the network
+// namespace it was opened in
+pub struct Netlink {
+    socket: Socket,
+    sequence: u32
+}
+
+impl Netlink {
+    pub fn open() -> Result<Netlink, ErrorType> {
+        Ok(Netlink { socket: open_socket(NETLINK_ROUTE)?, sequence: 0 })
+    }
+
+    // Sends a request and collects the messages of the response up to the acknowledgement,
+    // failing with the error the kernel answered instead, if any
+    fn request(&mut self, message: RtnlMessage, flags: u16, what: &str) -> Result<Vec<RtnlMessage>, ErrorType> {
+        let error = netlink_error(what);
+        self.sequence += 1;
+        let mut request = NetlinkMessage::from(message);
+        request.header.flags = NLM_F_REQUEST | NLM_F_ACK | flags;
+        request.header.sequence_number = self.sequence;
+        request.finalize();
+        let mut buffer = vec![0; request.buffer_len()];
+        request.serialize(&mut buffer);
+        self.socket.send(&buffer, 0).map_err(&error)?;
+        let mut responses = Vec::new();
+        loop {
+            let (buffer, _) = self.socket.recv_from_full().map_err(&error)?;
+            let mut offset = 0;
+            while offset < buffer.len() {
+                let response = NetlinkMessage::<RtnlMessage>::deserialize(&buffer[offset..])
+                    .map_err(|err| error(Error::new(ErrorKind::InvalidData, err.to_string())))?;
+                if response.header.length == 0 { break; }
+                offset += response.header.length as usize;
+                if response.header.sequence_number != self.sequence { continue; }
+                match response.payload {
+                    NetlinkPayload::Error(err) if err.code.is_some() => return Err(error(err.to_io())),
+                    NetlinkPayload::Error(_) | NetlinkPayload::Done(_) => return Ok(responses),
+                    NetlinkPayload::InnerMessage(message) => responses.push(message),
+                    _ => ()
+                }
+            }
+        }
+    }
+
+    // None if there is no such link
+    pub fn link_index(&mut self, name: &str) -> Result<Option<u32>, ErrorType> {
+        let mut message = LinkMessage::default();
+        message.nlas.push(LinkNla::IfName(name.to_string()));
+        match self.request(RtnlMessage::GetLink(message), 0, &format!("get link {}", name)) {
+            Ok(responses) => Ok(responses.into_iter().find_map(|response| match response {
+                RtnlMessage::NewLink(link) => Some(link.header.index),
+                _ => None
+            })),
+            Err(ErrorType::NetlinkError(err, _)) if err.raw_os_error() == Some(libc::ENODEV) => Ok(None),
+            Err(err) => Err(err)
+        }
+    }
+
+    fn create_link(&mut self, name: &str, kind: InfoKind, data: Option<InfoData>) -> Result<(), ErrorType> {
+        let what = format!("create link {}", name);
+        let mut message = LinkMessage::default();
+        message.nlas.push(LinkNla::IfName(name.to_string()));
+        let mut info = vec![Info::Kind(kind)];
+        if let Some(data) = data { info.push(Info::Data(data)); }
+        message.nlas.push(LinkNla::Info(info));
+        self.request(RtnlMessage::NewLink(message), NLM_F_CREATE | NLM_F_EXCL, &what)?;
+        Ok(())
+    }
+
+    pub fn create_bridge(&mut self, name: &str) -> Result<(), ErrorType> {
+        self.create_link(name, InfoKind::Bridge, None)
+    }
+
+    // Creates a veth pair whose peer end is created right in the network namespace of the process
+    pub fn create_veth(&mut self, name: &str, peer: &str, peer_pid: u32) -> Result<(), ErrorType> {
+        let mut peer_message = LinkMessage::default();
+        peer_message.nlas.push(LinkNla::IfName(peer.to_string()));
+        peer_message.nlas.push(LinkNla::NetNsPid(peer_pid));
+        self.create_link(name, InfoKind::Veth, Some(InfoData::Veth(VethInfo::Peer(peer_message))))
+    }
+
+    // Brings the link up, attached to the master link if any, e.g. a bridge
+    pub fn set_link_up(&mut self, index: u32, master: Option<u32>) -> Result<(), ErrorType> {
+        let mut message = LinkMessage::default();
+        message.header.index = index;
+        message.header.flags = IFF_UP;
+        message.header.change_mask = IFF_UP;
+        if let Some(master) = master { message.nlas.push(LinkNla::Master(master)); }
+        self.request(RtnlMessage::SetLink(message), 0, &format!("set up link {}", index))?;
+        Ok(())
+    }
+
+    pub fn delete_link(&mut self, index: u32) -> Result<(), ErrorType> {
+        let mut message = LinkMessage::default();
+        message.header.index = index;
+        self.request(RtnlMessage::DelLink(message), 0, &format!("delete link {}", index))?;
+        Ok(())
+    }
+
+    pub fn add_address(&mut self, index: u32, address: Ipv4Addr, prefix_len: u8) -> Result<(), ErrorType> {
+        let mut message = AddressMessage::default();
+        message.header.family = AF_INET as u8;
+        message.header.prefix_len = prefix_len;
+        message.header.index = index;
+        let broadcast = u32::from(address) | (u32::MAX.checked_shr(prefix_len as u32).unwrap_or(0));
+        message.nlas.push(AddressNla::Local(address.octets().to_vec()));
+        message.nlas.push(AddressNla::Address(address.octets().to_vec()));
+        message.nlas.push(AddressNla::Broadcast(Ipv4Addr::from(broadcast).octets().to_vec()));
+        let what = format!("add address {}/{} to link {}", address, prefix_len, index);
+        self.request(RtnlMessage::NewAddress(message), NLM_F_CREATE | NLM_F_EXCL, &what)?;
+        Ok(())
+    }
+
+    pub fn add_default_route(&mut self, gateway: Ipv4Addr) -> Result<(), ErrorType> {
+        let mut message = RouteMessage::default();
+        message.header.address_family = AF_INET as u8;
+        message.header.table = RT_TABLE_MAIN;
+        message.header.protocol = RTPROT_STATIC;
+        message.header.scope = RT_SCOPE_UNIVERSE;
+        message.header.kind = RTN_UNICAST;
+        message.nlas.push(RouteNla::Gateway(gateway.octets().to_vec()));
+        let what = format!("add default route via {}", gateway);
+        self.request(RtnlMessage::NewRoute(message), NLM_F_CREATE | NLM_F_EXCL, &what)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,254 @@
+use crate::errors::ErrorType;
+use crate::internal::join_namespace;
+use crate::ipam::{allocate_address, release_address};
+use crate::netlink::Netlink;
+use crate::nftables::{Batch, drop_to_loopback, masquerade, NF_INET_POST_ROUTING, NF_INET_PRE_ROUTING, PRIORITY_RAW, PRIORITY_SRCNAT};
+use crate::ports::{PublishedPort, publish_ports, unpublish_ports};
+
+use nix::sched::CloneFlags;
+use nix::unistd::Pid;
+
+use std::fs::{read_to_string, write};
+use std::net::Ipv4Addr;
+use std::path::PathBuf;
+
+pub const DEFAULT_NETWORK: &str = "bridge";
+pub const DEFAULT_BRIDGE: &str = "rucker0";
+pub const DEFAULT_SUBNET: &str = "10.88.0.0/16";
+const LOOPBACK_INTERFACE: &str = "lo";
+// The interface of a container, its host end being named after the container
+const CONTAINER_INTERFACE: &str = "eth0";
+const HOST_INTERFACE_PREFIX: &str = "veth";
+// Interface names are at most 15 bytes long
+const MAX_INTERFACE_NAME: usize = 15;
+// Holds the chains of each bridge: one masquerading what its containers send out of the host,
+// named after the bridge, and one keeping them off the host's loopback addresses
+const NAT_TABLE: &str = "rucker";
+const LOOPBACK_GUARD_SUFFIX: &str = "-localnet";
+const IP_FORWARD: &str = "/proc/sys/net/ipv4/ip_forward";
+
+// What the container's network is made of, see `run --network`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NetworkMode {
+    // An empty network namespace, its loopback interface left down
+    None,
+    Loopback,
+    // The host's network namespace
+    Host,
+    // Loopback, plus a veth pair to the bridge
+    Bridge
+}
+
+pub fn parse_network_mode(mode: &str) -> Result<NetworkMode, ErrorType> {
+    match mode {
+        "none" => Ok(NetworkMode::None),
+        "loopback" => Ok(NetworkMode::Loopback),
+        "host" => Ok(NetworkMode::Host),
+        "bridge" => Ok(NetworkMode::Bridge),
+        _ => Err(ErrorType::InvalidArgumentError(format!("unknown network {:?}, expected none, loopback, host or bridge", mode)))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Subnet {
+    pub network: Ipv4Addr,
+    pub prefix_len: u8
+}
+
+impl std::fmt::Display for Subnet {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix_len)
+    }
+}
+
+impl Subnet {
+    // "10.88.0.0/16", leaving room for the gateway and at least one container
+    pub fn parse(subnet: &str) -> Result<Subnet, ErrorType> {
+        let invalid = |reason: &str| ErrorType::InvalidArgumentError(format!("invalid subnet {:?}, {}", subnet, reason));
+        let (network, prefix_len) = subnet.split_once('/').ok_or_else(|| invalid("expected e.g. 10.88.0.0/16"))?;
+        let network: Ipv4Addr = network.parse().map_err(|_| invalid("expected an IPv4 network"))?;
+        let prefix_len: u8 = prefix_len.parse().ok().filter(|&prefix_len| prefix_len <= 30)
+            .ok_or_else(|| invalid("expected a prefix length of at most 30"))?;
+        let subnet = Subnet { network, prefix_len };
+        if u32::from(network) & !subnet.mask() != 0 { return Err(invalid("the address has host bits set")); }
+        Ok(subnet)
+    }
+
+    fn mask(&self) -> u32 {
+        u32::MAX.checked_shl(32 - self.prefix_len as u32).unwrap_or(0)
+    }
+
+    // The first address, given to the bridge
+    pub fn gateway(&self) -> Ipv4Addr {
+        Ipv4Addr::from(u32::from(self.network) + 1)
+    }
+
+    // The addresses left for containers, between the gateway and the broadcast address
+    pub fn hosts(&self) -> impl Iterator<Item = Ipv4Addr> {
+        let network = u32::from(self.network);
+        (network + 2..network | !self.mask()).map(Ipv4Addr::from)
+    }
+
+    pub fn contains_host(&self, address: Ipv4Addr) -> bool {
+        let address = u32::from(address);
+        address > u32::from(self.gateway()) && address < u32::from(self.network) | !self.mask()
+    }
+}
+
+// How a container is connected to the host: through a veth pair whose host end is attached to
+// the bridge, the container getting an address of the subnet, the given one if any
+pub struct NetworkConfig {
+    pub bridge: String,
+    pub subnet: Subnet,
+    pub ip: Option<Ipv4Addr>,
+    pub ports: Vec<PublishedPort>
+}
+
+impl NetworkConfig {
+    pub fn new(bridge: &str, subnet: &str, ip: Option<&str>, ports: Vec<PublishedPort>) -> Result<NetworkConfig, ErrorType> {
+        if bridge.is_empty() || bridge.len() > MAX_INTERFACE_NAME || bridge.contains(['/', ' ']) {
+            return Err(ErrorType::InvalidArgumentError(format!("invalid bridge name {:?}", bridge)));
+        }
+        let subnet = Subnet::parse(subnet)?;
+        let ip = match ip {
+            Some(ip) => match ip.parse() {
+                Ok(address) if subnet.contains_host(address) => Some(address),
+                _ => return Err(ErrorType::InvalidArgumentError(format!("invalid address {:?}, expected one of subnet {} other than its gateway", ip, subnet)))
+            },
+            None => None
+        };
+        Ok(NetworkConfig { bridge: bridge.to_string(), subnet, ip, ports })
+    }
+}
+
+fn host_interface(id: &str) -> String {
+    let suffix: String = id.chars().take(MAX_INTERFACE_NAME - HOST_INTERFACE_PREFIX.len()).collect();
+    format!("{}{}", HOST_INTERFACE_PREFIX, suffix)
+}
+
+// Forwarding is off by default, and without it nothing the containers send leaves the host
+fn enable_forwarding() -> Result<(), ErrorType> {
+    if read_to_string(IP_FORWARD).map_err(ErrorType::FileError)?.trim() == "1" { return Ok(()); }
+    write(IP_FORWARD, "1").map_err(ErrorType::FileError)?;
+    log::info!("Enabled IPv4 forwarding");
+    Ok(())
+}
+
+// Replaced on every run rather than appended to, the batch applying atomically
+fn add_bridge_rules(config: &NetworkConfig) -> Result<(), ErrorType> {
+    let mut batch = Batch::new();
+    batch.add_table(NAT_TABLE);
+    batch.add_nat_chain(NAT_TABLE, &config.bridge, NF_INET_POST_ROUTING, PRIORITY_SRCNAT);
+    batch.flush_chain(NAT_TABLE, &config.bridge);
+    batch.add_rule(NAT_TABLE, &config.bridge, &masquerade(config.subnet.network, config.subnet.prefix_len, &config.bridge));
+    // Publishing ports to 127.0.0.1 sets route_localnet on the bridge, which would otherwise let
+    // containers reach services the host only listens to on its loopback addresses
+    let guard = format!("{}{}", config.bridge, LOOPBACK_GUARD_SUFFIX);
+    batch.add_filter_chain(NAT_TABLE, &guard, NF_INET_PRE_ROUTING, PRIORITY_RAW);
+    batch.flush_chain(NAT_TABLE, &guard);
+    batch.add_rule(NAT_TABLE, &guard, &drop_to_loopback(&config.bridge));
+    batch.commit(&format!("masquerade subnet {}", config.subnet))
+}
+
+// Creates the bridge with the gateway address if missing, along with the rules masquerading its
+// subnet, and returns its index and whether the rules are in place. Without them, e.g. on a host
+// without nf_tables, containers can still reach the host and each other.
+// Another rucker may be doing the same concurrently
+fn setup_bridge(netlink: &mut Netlink, config: &NetworkConfig) -> Result<(u32, bool), ErrorType> {
+    let already_exists = |err: &ErrorType| matches!(err, ErrorType::NetlinkError(err, _) if err.raw_os_error() == Some(libc::EEXIST));
+    if netlink.link_index(&config.bridge)?.is_none() {
+        match netlink.create_bridge(&config.bridge) {
+            Ok(()) => log::info!("Created bridge {}", config.bridge),
+            Err(err) if already_exists(&err) => (),
+            Err(err) => return Err(err)
+        }
+    }
+    let bridge = netlink.link_index(&config.bridge)?
+        .ok_or_else(|| ErrorType::InvalidStateError(format!("bridge {} vanished", config.bridge)))?;
+    match netlink.add_address(bridge, config.subnet.gateway(), config.subnet.prefix_len) {
+        Err(err) if !already_exists(&err) => return Err(err),
+        _ => ()
+    }
+    netlink.set_link_up(bridge, None)?;
+    if let Err(err) = enable_forwarding() {
+        log::warn!("Failed to enable IPv4 forwarding, containers won't reach beyond the host: {:?}", err);
+    }
+    let rules = match add_bridge_rules(config) {
+        Ok(()) => true,
+        Err(err) => {
+            log::warn!("Failed to masquerade subnet {}, containers won't reach beyond the host: {:?}", config.subnet, err);
+            false
+        }
+    };
+    Ok((bridge, rules))
+}
+
+// Runs the closure in a thread that joins the network namespace of the process,
+// netlink sockets belonging to the namespace they are opened in
+fn in_network_namespace<T: Send>(pid: Pid, f: impl FnOnce() -> Result<T, ErrorType> + Send) -> Result<T, ErrorType> {
+    let path = PathBuf::from(format!("/proc/{}/ns/net", pid));
+    std::thread::scope(|scope| scope.spawn(|| {
+        join_namespace(CloneFlags::CLONE_NEWNET, &path)?;
+        f()
+    }).join().expect("PanickedException: The network setup thread panicked"))
+}
+
+// Run by the child in its new network namespace, where even the loopback interface starts down,
+// before it gives up its capabilities over the namespace
+pub fn bring_up_loopback() -> Result<(), ErrorType> {
+    let mut netlink = Netlink::open()?;
+    let index = netlink.link_index(LOOPBACK_INTERFACE)?
+        .ok_or_else(|| ErrorType::InvalidStateError(format!("no {} interface", LOOPBACK_INTERFACE)))?;
+    netlink.set_link_up(index, None)?;
+    log::debug!("Brought up the loopback interface");
+    Ok(())
+}
+
+// Connects the network namespace of the container's process to the bridge,
+// and returns the address the container was given
+pub fn setup_network(id: &str, pid: Pid, config: &NetworkConfig) -> Result<Ipv4Addr, ErrorType> {
+    let address = allocate_address(id, pid, &config.subnet, config.ip)?;
+    let mut netlink = Netlink::open()?;
+    let (bridge, rules) = setup_bridge(&mut netlink, config)?;
+    let host_interface = host_interface(id);
+    netlink.create_veth(&host_interface, CONTAINER_INTERFACE, pid.as_raw() as u32)?;
+    let index = netlink.link_index(&host_interface)?
+        .ok_or_else(|| ErrorType::InvalidStateError(format!("interface {} vanished", host_interface)))?;
+    netlink.set_link_up(index, Some(bridge))?;
+    in_network_namespace(pid, || {
+        let mut netlink = Netlink::open()?;
+        let index = netlink.link_index(CONTAINER_INTERFACE)?
+            .ok_or_else(|| ErrorType::InvalidStateError(format!("interface {} vanished", CONTAINER_INTERFACE)))?;
+        netlink.add_address(index, address, config.subnet.prefix_len)?;
+        netlink.set_link_up(index, None)?;
+        netlink.add_default_route(config.subnet.gateway())
+    })?;
+    log::info!("Connected container {} to bridge {} with address {}", id, config.bridge, address);
+    publish_ports(id, address, &config.bridge, &config.ports, rules)?;
+    Ok(address)
+}
+
+// The host end of the veth pair goes away along with the container's network namespace, but only
+// once the kernel got around to destroying it
+fn delete_host_interface(id: &str) -> Result<(), ErrorType> {
+    let mut netlink = Netlink::open()?;
+    if let Some(index) = netlink.link_index(&host_interface(id))? {
+        match netlink.delete_link(index) {
+            Err(ErrorType::NetlinkError(err, _)) if err.raw_os_error() == Some(libc::ENODEV) => (),
+            result => result?
+        }
+    }
+    Ok(())
+}
+
+// Keeps going when a step fails, e.g. unpublishing ports that were proxied because nftables
+// can't be used, the address being released last whatever happened, so that it isn't given to
+// another container before its last user is gone
+pub fn teardown_network(id: &str, config: &NetworkConfig) -> Result<(), ErrorType> {
+    if !config.ports.is_empty() {
+        if let Err(err) = unpublish_ports(id) { log::warn!("Failed to unpublish the ports of container {}: {:?}", id, err); }
+    }
+    let result = delete_host_interface(id);
+    release_address(id)?;
+    result
+}
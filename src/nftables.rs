@@ -0,0 +1,335 @@
+use crate::errors::ErrorType;
+use crate::netlink::{open_socket, split_messages};
+
+use netlink_sys::protocols::NETLINK_NETFILTER;
+
+use std::io::Error;
+use std::net::Ipv4Addr;
+
+// Only the bits of the nf_tables netlink API rucker uses, see linux/netfilter/nf_tables.h
+const NFNL_SUBSYS_NFTABLES: u16 = 10;
+const NFNL_MSG_BATCH_BEGIN: u16 = 16;
+const NFNL_MSG_BATCH_END: u16 = 17;
+const NFT_MSG_NEWTABLE: u16 = 0;
+const NFT_MSG_DELTABLE: u16 = 2;
+const NFT_MSG_NEWCHAIN: u16 = 3;
+const NFT_MSG_NEWRULE: u16 = 6;
+const NFT_MSG_DELRULE: u16 = 8;
+const NFPROTO_IPV4: u8 = 2;
+const NLMSG_ERROR: u16 = 2;
+const NLA_F_NESTED: u16 = 1 << 15;
+
+const NFTA_TABLE_NAME: u16 = 1;
+const NFTA_CHAIN_TABLE: u16 = 1;
+const NFTA_CHAIN_NAME: u16 = 3;
+const NFTA_CHAIN_HOOK: u16 = 4;
+const NFTA_CHAIN_TYPE: u16 = 7;
+const NFTA_HOOK_HOOKNUM: u16 = 1;
+const NFTA_HOOK_PRIORITY: u16 = 2;
+const NFTA_RULE_TABLE: u16 = 1;
+const NFTA_RULE_CHAIN: u16 = 2;
+const NFTA_RULE_EXPRESSIONS: u16 = 4;
+const NFTA_LIST_ELEM: u16 = 1;
+const NFTA_EXPR_NAME: u16 = 1;
+const NFTA_EXPR_DATA: u16 = 2;
+const NFTA_DATA_VALUE: u16 = 1;
+const NFTA_DATA_VERDICT: u16 = 2;
+const NFTA_VERDICT_CODE: u16 = 1;
+
+// Expressions load into and compare from the first 16-byte register, the second one
+// only holding the port of a DNAT
+const NFT_REG_1: u32 = 1;
+const NFT_REG_2: u32 = 2;
+const NFT_REG_VERDICT: u32 = 0;
+const NF_DROP: u32 = 0;
+const NFT_PAYLOAD_NETWORK_HEADER: u32 = 1;
+const NFT_PAYLOAD_TRANSPORT_HEADER: u32 = 2;
+const NFT_CMP_EQ: u32 = 0;
+const NFT_CMP_NEQ: u32 = 1;
+const NFT_META_IIFNAME: u32 = 6;
+const NFT_META_OIFNAME: u32 = 7;
+const NFT_META_L4PROTO: u32 = 16;
+const NFT_FIB_RESULT_ADDRTYPE: u32 = 3;
+const NFTA_FIB_F_DADDR: u32 = 1 << 1;
+const NFT_NAT_DNAT: u32 = 1;
+const RTN_LOCAL: u32 = 2;
+// Offsets of the addresses in the IPv4 header, and of the destination port in the TCP and UDP ones
+const IPV4_SADDR_OFFSET: u32 = 12;
+const IPV4_DADDR_OFFSET: u32 = 16;
+const DPORT_OFFSET: u32 = 2;
+const IFNAMSIZ: usize = 16;
+
+// The netfilter hooks a base chain is attached to, and the priorities of the chains as named by nft
+pub const NF_INET_PRE_ROUTING: u32 = 0;
+pub const NF_INET_LOCAL_OUT: u32 = 3;
+pub const NF_INET_POST_ROUTING: u32 = 4;
+pub const PRIORITY_RAW: i32 = -300;
+pub const PRIORITY_DSTNAT: i32 = -100;
+pub const PRIORITY_SRCNAT: i32 = 100;
+
+// Netlink attributes, appended one after the other, each aligned on 4 bytes
+#[derive(Default)]
+struct Attributes(Vec<u8>);
+
+impl Attributes {
+    fn put(&mut self, kind: u16, value: &[u8]) -> &mut Attributes {
+        self.0.extend_from_slice(&((4 + value.len()) as u16).to_ne_bytes());
+        self.0.extend_from_slice(&kind.to_ne_bytes());
+        self.0.extend_from_slice(value);
+        self.0.resize((self.0.len() + 3) & !3, 0);
+        self
+    }
+
+    fn put_str(&mut self, kind: u16, value: &str) -> &mut Attributes {
+        self.put(kind, format!("{}\0", value).as_bytes())
+    }
+
+    // nf_tables wants its integers in network byte order
+    fn put_u32(&mut self, kind: u16, value: u32) -> &mut Attributes {
+        self.put(kind, &value.to_be_bytes())
+    }
+
+    fn put_nested(&mut self, kind: u16, nested: &Attributes) -> &mut Attributes {
+        self.put(kind | NLA_F_NESTED, &nested.0)
+    }
+
+    fn put_data(&mut self, kind: u16, data: &[u8]) -> &mut Attributes {
+        let mut value = Attributes::default();
+        value.put(NFTA_DATA_VALUE, data);
+        self.put_nested(kind, &value)
+    }
+}
+
+// An expression of a rule, e.g. a comparison or a verdict
+pub struct Expression(Attributes);
+
+fn expression(name: &str, data: Attributes) -> Expression {
+    let mut expression = Attributes::default();
+    expression.put_str(NFTA_EXPR_NAME, name).put_nested(NFTA_EXPR_DATA, &data);
+    Expression(expression)
+}
+
+// The data attributes of the expressions are numbered from 1 in the order of the constants of
+// nf_tables.h, e.g. NFTA_PAYLOAD_DREG, NFTA_PAYLOAD_BASE, NFTA_PAYLOAD_OFFSET, NFTA_PAYLOAD_LEN
+fn payload(base: u32, offset: u32, len: u32) -> Expression {
+    let mut data = Attributes::default();
+    data.put_u32(1, NFT_REG_1).put_u32(2, base).put_u32(3, offset).put_u32(4, len);
+    expression("payload", data)
+}
+
+// Masks the register, XORing it with zeros
+fn bitwise(mask: &[u8]) -> Expression {
+    let mut data = Attributes::default();
+    data.put_u32(1, NFT_REG_1).put_u32(2, NFT_REG_1).put_u32(3, mask.len() as u32)
+        .put_data(4, mask).put_data(5, &vec![0; mask.len()]);
+    expression("bitwise", data)
+}
+
+fn cmp(op: u32, value: &[u8]) -> Expression {
+    let mut data = Attributes::default();
+    data.put_u32(1, NFT_REG_1).put_u32(2, op).put_data(3, value);
+    expression("cmp", data)
+}
+
+fn meta(key: u32) -> Expression {
+    let mut data = Attributes::default();
+    data.put_u32(1, NFT_REG_1).put_u32(2, key);
+    expression("meta", data)
+}
+
+// The type of the destination address, as the routing table has it
+fn fib_daddr_type() -> Expression {
+    let mut data = Attributes::default();
+    data.put_u32(1, NFT_REG_1).put_u32(2, NFT_FIB_RESULT_ADDRTYPE).put_u32(3, NFTA_FIB_F_DADDR);
+    expression("fib", data)
+}
+
+fn immediate(register: u32, value: &[u8]) -> Expression {
+    let mut data = Attributes::default();
+    data.put_u32(1, register).put_data(2, value);
+    expression("immediate", data)
+}
+
+fn verdict(code: u32) -> Expression {
+    let mut verdict = Attributes::default();
+    verdict.put_u32(NFTA_VERDICT_CODE, code);
+    let mut value = Attributes::default();
+    value.put_nested(NFTA_DATA_VERDICT, &verdict);
+    let mut data = Attributes::default();
+    data.put_u32(1, NFT_REG_VERDICT).put_nested(2, &value);
+    expression("immediate", data)
+}
+
+// DNAT to the address in the first register and the port in the second one
+fn dnat_registers() -> Expression {
+    let mut data = Attributes::default();
+    data.put_u32(1, NFT_NAT_DNAT).put_u32(2, NFPROTO_IPV4 as u32).put_u32(3, NFT_REG_1).put_u32(5, NFT_REG_2);
+    expression("nat", data)
+}
+
+// ip saddr <network>/<prefix_len>
+fn source_in(network: Ipv4Addr, prefix_len: u8) -> Vec<Expression> {
+    let mask = u32::MAX.checked_shl(32 - prefix_len as u32).unwrap_or(0);
+    vec![
+        payload(NFT_PAYLOAD_NETWORK_HEADER, IPV4_SADDR_OFFSET, 4),
+        bitwise(&mask.to_be_bytes()),
+        cmp(NFT_CMP_EQ, &network.octets())
+    ]
+}
+
+// Interface names are compared over IFNAMSIZ bytes, padded with zeros
+fn interface_name(name: &str) -> Vec<u8> {
+    let mut bytes = name.as_bytes().to_vec();
+    bytes.resize(IFNAMSIZ, 0);
+    bytes
+}
+
+// ip saddr <network>/<prefix_len> oifname != <interface> masquerade
+pub fn masquerade(network: Ipv4Addr, prefix_len: u8, interface: &str) -> Vec<Expression> {
+    let mut rule = source_in(network, prefix_len);
+    rule.extend([
+        meta(NFT_META_OIFNAME),
+        cmp(NFT_CMP_NEQ, &interface_name(interface)),
+        expression("masq", Attributes::default())
+    ]);
+    rule
+}
+
+// ip saddr <network>/<prefix_len> ip daddr <destination> masquerade
+pub fn masquerade_to(network: Ipv4Addr, prefix_len: u8, destination: Ipv4Addr) -> Vec<Expression> {
+    let mut rule = source_in(network, prefix_len);
+    rule.extend([
+        payload(NFT_PAYLOAD_NETWORK_HEADER, IPV4_DADDR_OFFSET, 4),
+        cmp(NFT_CMP_EQ, &destination.octets()),
+        expression("masq", Attributes::default())
+    ]);
+    rule
+}
+
+// iifname <interface> ip daddr 127.0.0.0/8 drop
+pub fn drop_to_loopback(interface: &str) -> Vec<Expression> {
+    vec![
+        meta(NFT_META_IIFNAME),
+        cmp(NFT_CMP_EQ, &interface_name(interface)),
+        payload(NFT_PAYLOAD_NETWORK_HEADER, IPV4_DADDR_OFFSET, 1),
+        cmp(NFT_CMP_EQ, &[127]),
+        verdict(NF_DROP)
+    ]
+}
+
+// fib daddr type local meta l4proto <protocol> th dport <port> dnat to <address>:<to_port>
+pub fn dnat(protocol: u8, port: u16, address: Ipv4Addr, to_port: u16) -> Vec<Expression> {
+    vec![
+        fib_daddr_type(),
+        // The address type is stored in host byte order
+        cmp(NFT_CMP_EQ, &RTN_LOCAL.to_ne_bytes()),
+        meta(NFT_META_L4PROTO),
+        cmp(NFT_CMP_EQ, &[protocol]),
+        payload(NFT_PAYLOAD_TRANSPORT_HEADER, DPORT_OFFSET, 2),
+        cmp(NFT_CMP_EQ, &port.to_be_bytes()),
+        immediate(NFT_REG_1, &address.octets()),
+        immediate(NFT_REG_2, &to_port.to_be_bytes()),
+        dnat_registers()
+    ]
+}
+
+// Changes to the ruleset, applied all at once or not at all by commit
+pub struct Batch {
+    buffer: Vec<u8>,
+    // Messages the kernel acknowledges, the batch delimiters left aside
+    messages: u32
+}
+
+impl Batch {
+    pub fn new() -> Batch {
+        let mut batch = Batch { buffer: Vec::new(), messages: 0 };
+        batch.push(NFNL_MSG_BATCH_BEGIN, 0, 0, &Attributes::default());
+        batch
+    }
+
+    // A netlink header, then the nfgenmsg header, whose resource ID only batch delimiters set
+    fn push(&mut self, kind: u16, flags: u16, family: u8, attributes: &Attributes) {
+        let is_delimiter = kind == NFNL_MSG_BATCH_BEGIN || kind == NFNL_MSG_BATCH_END;
+        let (kind, resource, flags) = if is_delimiter {
+            (kind, NFNL_SUBSYS_NFTABLES, libc::NLM_F_REQUEST as u16)
+        } else {
+            self.messages += 1;
+            (NFNL_SUBSYS_NFTABLES << 8 | kind, 0, libc::NLM_F_REQUEST as u16 | libc::NLM_F_ACK as u16 | flags)
+        };
+        let length = 16 + 4 + attributes.0.len();
+        self.buffer.extend_from_slice(&(length as u32).to_ne_bytes());
+        self.buffer.extend_from_slice(&kind.to_ne_bytes());
+        self.buffer.extend_from_slice(&flags.to_ne_bytes());
+        self.buffer.extend_from_slice(&self.messages.to_ne_bytes());
+        self.buffer.extend_from_slice(&0u32.to_ne_bytes());
+        self.buffer.extend_from_slice(&[family, 0]);
+        self.buffer.extend_from_slice(&resource.to_be_bytes());
+        self.buffer.extend_from_slice(&attributes.0);
+    }
+
+    pub fn add_table(&mut self, table: &str) {
+        let mut attributes = Attributes::default();
+        attributes.put_str(NFTA_TABLE_NAME, table);
+        self.push(NFT_MSG_NEWTABLE, libc::NLM_F_CREATE as u16, NFPROTO_IPV4, &attributes);
+    }
+
+    // Along with its chains and rules
+    pub fn delete_table(&mut self, table: &str) {
+        let mut attributes = Attributes::default();
+        attributes.put_str(NFTA_TABLE_NAME, table);
+        self.push(NFT_MSG_DELTABLE, 0, NFPROTO_IPV4, &attributes);
+    }
+
+    fn add_base_chain(&mut self, table: &str, chain: &str, kind: &str, hook: u32, priority: i32) {
+        let mut hook_attributes = Attributes::default();
+        hook_attributes.put_u32(NFTA_HOOK_HOOKNUM, hook).put_u32(NFTA_HOOK_PRIORITY, priority as u32);
+        let mut attributes = Attributes::default();
+        attributes.put_str(NFTA_CHAIN_TABLE, table).put_str(NFTA_CHAIN_NAME, chain)
+            .put_nested(NFTA_CHAIN_HOOK, &hook_attributes).put_str(NFTA_CHAIN_TYPE, kind);
+        self.push(NFT_MSG_NEWCHAIN, libc::NLM_F_CREATE as u16, NFPROTO_IPV4, &attributes);
+    }
+
+    pub fn add_nat_chain(&mut self, table: &str, chain: &str, hook: u32, priority: i32) {
+        self.add_base_chain(table, chain, "nat", hook, priority);
+    }
+
+    pub fn add_filter_chain(&mut self, table: &str, chain: &str, hook: u32, priority: i32) {
+        self.add_base_chain(table, chain, "filter", hook, priority);
+    }
+
+    // Deletes the rules of the chain
+    pub fn flush_chain(&mut self, table: &str, chain: &str) {
+        let mut attributes = Attributes::default();
+        attributes.put_str(NFTA_RULE_TABLE, table).put_str(NFTA_RULE_CHAIN, chain);
+        self.push(NFT_MSG_DELRULE, 0, NFPROTO_IPV4, &attributes);
+    }
+
+    pub fn add_rule(&mut self, table: &str, chain: &str, rule: &[Expression]) {
+        let mut expressions = Attributes::default();
+        for expression in rule.iter() { expressions.put_nested(NFTA_LIST_ELEM, &expression.0); }
+        let mut attributes = Attributes::default();
+        attributes.put_str(NFTA_RULE_TABLE, table).put_str(NFTA_RULE_CHAIN, chain)
+            .put_nested(NFTA_RULE_EXPRESSIONS, &expressions);
+        self.push(NFT_MSG_NEWRULE, (libc::NLM_F_CREATE | libc::NLM_F_APPEND) as u16, NFPROTO_IPV4, &attributes);
+    }
+
+    // Sends the batch and waits for the kernel to acknowledge each of its messages,
+    // failing with the first error it reports
+    pub fn commit(mut self, what: &str) -> Result<(), ErrorType> {
+        let error = |err| ErrorType::NetlinkError(err, what.to_string());
+        self.push(NFNL_MSG_BATCH_END, 0, 0, &Attributes::default());
+        let socket = open_socket(NETLINK_NETFILTER)?;
+        socket.send(&self.buffer, 0).map_err(error)?;
+        let mut pending = self.messages;
+        while pending > 0 {
+            let (buffer, _) = socket.recv_from_full().map_err(error)?;
+            for (kind, _, _, payload) in split_messages(&buffer) {
+                if kind != NLMSG_ERROR || payload.len() < 4 { continue; }
+                let code = i32::from_ne_bytes(payload[..4].try_into().unwrap());
+                if code != 0 { return Err(error(Error::from_raw_os_error(-code))); }
+                pending = pending.saturating_sub(1);
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,569 @@
+use crate::errors::ErrorType;
+use crate::config::{ContainerConfig, IdMapping, Mount};
+use crate::rlimits::{Rlimit, parse_resource};
+use crate::cgroup::{DeviceLimit, Resources, Throttle, clean_cgroup, hierarchy_path, parse_cgroup_path, signal_cgroup};
+use crate::container::Container;
+use crate::childproc::ChildProcess;
+use crate::state::{ContainerState, Status, validate_id, state_dir, exec_fifo_path};
+use crate::syscalls::{SeccompProfile, SeccompRule};
+use crate::utils::parse_signal;
+use crate::{CreateOptions, DeleteOptions, KillOptions, StopOptions};
+
+use capctl::caps::Cap;
+use nix::fcntl::{open, OFlag};
+use nix::mount::MsFlags;
+use nix::sched::CloneFlags;
+use nix::sys::signal::Signal;
+use nix::sys::stat::Mode;
+use nix::unistd::{mkfifo, write, Pid};
+use serde::{Deserialize, Serialize};
+use syscallz::{Action, Cmp, Comparator, Syscall};
+
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::fs::{create_dir_all, read_to_string, remove_file};
+use std::os::fd::{FromRawFd, OwnedFd};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::thread::sleep;
+use std::time::Duration;
+
+pub const OCI_VERSION: &str = "1.0.2";
+const CONFIG_FILE: &str = "config.json";
+const DELETE_TIMEOUT: Duration = Duration::from_secs(10);
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// The subset of the OCI runtime spec (config.json) that rucker understands
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Spec {
+    pub oci_version: String,
+    pub process: Option<Process>,
+    pub root: Option<Root>,
+    pub hostname: Option<String>,
+    #[serde(default)]
+    pub mounts: Vec<SpecMount>,
+    pub linux: Option<Linux>
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Process {
+    #[serde(default)]
+    pub terminal: bool,
+    #[serde(default)]
+    pub user: User,
+    pub args: Vec<String>,
+    #[serde(default)]
+    pub env: Vec<String>,
+    pub cwd: PathBuf,
+    pub capabilities: Option<Capabilities>,
+    #[serde(default)]
+    pub rlimits: Vec<SpecRlimit>
+}
+
+#[derive(Deserialize)]
+pub struct SpecRlimit {
+    #[serde(rename = "type")]
+    pub rltype: String,
+    pub soft: u64,
+    pub hard: u64
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct User {
+    #[serde(default)]
+    pub uid: u32,
+    #[serde(default)]
+    pub gid: u32
+}
+
+#[derive(Deserialize)]
+pub struct Capabilities {
+    #[serde(default)]
+    pub bounding: Vec<String>
+}
+
+#[derive(Deserialize)]
+pub struct Root {
+    pub path: PathBuf,
+    #[serde(default)]
+    pub readonly: bool
+}
+
+#[derive(Deserialize)]
+pub struct SpecMount {
+    pub destination: PathBuf,
+    #[serde(rename = "type")]
+    pub fstype: Option<String>,
+    pub source: Option<PathBuf>,
+    #[serde(default)]
+    pub options: Vec<String>
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Linux {
+    #[serde(default)]
+    pub namespaces: Vec<Namespace>,
+    #[serde(default)]
+    pub uid_mappings: Vec<SpecIdMapping>,
+    #[serde(default)]
+    pub gid_mappings: Vec<SpecIdMapping>,
+    pub resources: Option<SpecResources>,
+    // Absolute paths are relative to the root of the cgroup hierarchies, relative ones to rucker's parent cgroup
+    pub cgroups_path: Option<String>,
+    pub seccomp: Option<Seccomp>
+}
+
+#[derive(Deserialize)]
+pub struct Namespace {
+    #[serde(rename = "type")]
+    pub nstype: String,
+    pub path: Option<PathBuf>
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SpecIdMapping {
+    #[serde(rename = "containerID")]
+    pub container_id: u32,
+    #[serde(rename = "hostID")]
+    pub host_id: u32,
+    pub size: u32
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SpecResources {
+    pub memory: Option<MemoryResources>,
+    pub cpu: Option<CpuResources>,
+    pub pids: Option<PidsResources>,
+    #[serde(rename = "blockIO")]
+    pub block_io: Option<BlockIoResources>
+}
+
+#[derive(Deserialize)]
+pub struct MemoryResources {
+    pub limit: Option<i64>,
+    pub swap: Option<i64>,
+    pub reservation: Option<i64>,
+    // Deprecated by the runtime spec, and absent from cgroup v2
+    pub kernel: Option<i64>
+}
+
+#[derive(Deserialize)]
+pub struct CpuResources {
+    pub shares: Option<u64>,
+    pub quota: Option<i64>,
+    pub period: Option<u64>,
+    pub cpus: Option<String>,
+    pub mems: Option<String>
+}
+
+#[derive(Deserialize)]
+pub struct PidsResources {
+    pub limit: i64
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BlockIoResources {
+    pub weight: Option<u16>,
+    #[serde(default)]
+    pub throttle_read_bps_device: Vec<ThrottleDevice>,
+    #[serde(default)]
+    pub throttle_write_bps_device: Vec<ThrottleDevice>,
+    #[serde(default, rename = "throttleReadIOPSDevice")]
+    pub throttle_read_iops_device: Vec<ThrottleDevice>,
+    #[serde(default, rename = "throttleWriteIOPSDevice")]
+    pub throttle_write_iops_device: Vec<ThrottleDevice>
+}
+
+#[derive(Deserialize)]
+pub struct ThrottleDevice {
+    pub major: u64,
+    pub minor: u64,
+    pub rate: u64
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Seccomp {
+    pub default_action: String,
+    pub default_errno_ret: Option<u16>,
+    #[serde(default)]
+    pub syscalls: Vec<SeccompSyscall>
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SeccompSyscall {
+    pub names: Vec<String>,
+    pub action: String,
+    pub errno_ret: Option<u16>,
+    #[serde(default)]
+    pub args: Vec<SeccompArg>
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SeccompArg {
+    pub index: u32,
+    pub value: u64,
+    #[serde(default)]
+    pub value_two: u64,
+    pub op: String
+}
+
+// The state of a container as printed by `rucker state`, per the OCI runtime spec
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct OciState<'a> {
+    oci_version: &'a str,
+    id: &'a str,
+    status: Status,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pid: Option<i32>,
+    bundle: &'a Path,
+    annotations: &'a BTreeMap<String, String>
+}
+
+fn spec_error(message: String) -> ErrorType {
+    ErrorType::SpecError(message)
+}
+
+pub fn load_spec(bundle: &Path) -> Result<Spec, ErrorType> {
+    let content = read_to_string(bundle.join(CONFIG_FILE)).map_err(ErrorType::FileError)?;
+    let spec: Spec = serde_json::from_str(&content).map_err(ErrorType::JsonError)?;
+    if !spec.oci_version.starts_with("1.") {
+        log::warn!("Unsupported OCI version {}, proceeding anyway", spec.oci_version);
+    }
+    Ok(spec)
+}
+
+fn parse_mount_options(options: &[String]) -> (MsFlags, Option<String>) {
+    let mut flags = MsFlags::empty();
+    let mut data = Vec::new();
+    for option in options.iter() {
+        match option.as_str() {
+            "ro" => flags.insert(MsFlags::MS_RDONLY),
+            "rw" => flags.remove(MsFlags::MS_RDONLY),
+            "nosuid" => flags.insert(MsFlags::MS_NOSUID),
+            "nodev" => flags.insert(MsFlags::MS_NODEV),
+            "noexec" => flags.insert(MsFlags::MS_NOEXEC),
+            "sync" => flags.insert(MsFlags::MS_SYNCHRONOUS),
+            "dirsync" => flags.insert(MsFlags::MS_DIRSYNC),
+            "remount" => flags.insert(MsFlags::MS_REMOUNT),
+            "mand" => flags.insert(MsFlags::MS_MANDLOCK),
+            "noatime" => flags.insert(MsFlags::MS_NOATIME),
+            "nodiratime" => flags.insert(MsFlags::MS_NODIRATIME),
+            "relatime" => flags.insert(MsFlags::MS_RELATIME),
+            "strictatime" => flags.insert(MsFlags::MS_STRICTATIME),
+            "bind" => flags.insert(MsFlags::MS_BIND),
+            "rbind" => flags.insert(MsFlags::MS_BIND | MsFlags::MS_REC),
+            "private" => flags.insert(MsFlags::MS_PRIVATE),
+            "rprivate" => flags.insert(MsFlags::MS_PRIVATE | MsFlags::MS_REC),
+            "slave" => flags.insert(MsFlags::MS_SLAVE),
+            "rslave" => flags.insert(MsFlags::MS_SLAVE | MsFlags::MS_REC),
+            "shared" => flags.insert(MsFlags::MS_SHARED),
+            "rshared" => flags.insert(MsFlags::MS_SHARED | MsFlags::MS_REC),
+            "unbindable" => flags.insert(MsFlags::MS_UNBINDABLE),
+            "runbindable" => flags.insert(MsFlags::MS_UNBINDABLE | MsFlags::MS_REC),
+            "suid" | "dev" | "exec" | "async" | "atime" | "diratime" | "defaults" => {},
+            _ => data.push(option.clone())
+        }
+    }
+    (flags, if data.is_empty() { None } else { Some(data.join(",")) })
+}
+
+fn convert_mount(mnt: &SpecMount, bundle: &Path) -> Mount {
+    let (mut flags, data) = parse_mount_options(&mnt.options);
+    if mnt.fstype.as_deref() == Some("bind") { flags.insert(MsFlags::MS_BIND); }
+    let source = mnt.source.as_ref().map(|source| {
+        if flags.contains(MsFlags::MS_BIND) && source.is_relative() { bundle.join(source) } else { source.clone() }
+    });
+    let fstype = mnt.fstype.clone().filter(|fstype| fstype != "bind" && !flags.contains(MsFlags::MS_BIND));
+    Mount { source, destination: mnt.destination.clone(), fstype, flags, data }
+}
+
+fn namespace_flag(nstype: &str) -> Result<CloneFlags, ErrorType> {
+    match nstype {
+        "pid" => Ok(CloneFlags::CLONE_NEWPID),
+        "network" => Ok(CloneFlags::CLONE_NEWNET),
+        "mount" => Ok(CloneFlags::CLONE_NEWNS),
+        "ipc" => Ok(CloneFlags::CLONE_NEWIPC),
+        "uts" => Ok(CloneFlags::CLONE_NEWUTS),
+        "user" => Ok(CloneFlags::CLONE_NEWUSER),
+        "cgroup" => Ok(CloneFlags::CLONE_NEWCGROUP),
+        _ => Err(spec_error(format!("unknown namespace type {}", nstype)))
+    }
+}
+
+fn convert_resources(resources: &SpecResources) -> Resources {
+    let mut converted = Resources::unlimited();
+    if let Some(memory) = &resources.memory {
+        converted.memory_limit = memory.limit;
+        converted.memory_swap = memory.swap;
+        converted.memory_reservation = memory.reservation;
+        if memory.kernel.is_some() { log::warn!("Kernel memory limits are not supported, linux.resources.memory.kernel is ignored"); }
+    }
+    if let Some(cpu) = &resources.cpu {
+        converted.cpu_shares = cpu.shares;
+        converted.cpu_quota = cpu.quota;
+        converted.cpu_period = cpu.period;
+        converted.cpuset_cpus = cpu.cpus.clone();
+        converted.cpuset_mems = cpu.mems.clone();
+    }
+    converted.pids_limit = resources.pids.as_ref().map(|pids| pids.limit);
+    if let Some(block_io) = &resources.block_io {
+        converted.blkio_weight = block_io.weight;
+        let throttles = [
+            (&block_io.throttle_read_bps_device, Throttle::ReadBps), (&block_io.throttle_write_bps_device, Throttle::WriteBps),
+            (&block_io.throttle_read_iops_device, Throttle::ReadIops), (&block_io.throttle_write_iops_device, Throttle::WriteIops)
+        ];
+        for (devices, throttle) in throttles {
+            converted.device_limits.extend(devices.iter()
+                .map(|device| DeviceLimit { major: device.major, minor: device.minor, throttle, rate: device.rate }));
+        }
+    }
+    converted
+}
+
+fn seccomp_action(action: &str, errno_ret: Option<u16>) -> Result<Action, ErrorType> {
+    match action {
+        "SCMP_ACT_ALLOW" => Ok(Action::Allow),
+        "SCMP_ACT_ERRNO" => Ok(Action::Errno(errno_ret.unwrap_or(libc::EPERM as u16))),
+        "SCMP_ACT_KILL" | "SCMP_ACT_KILL_THREAD" => Ok(Action::KillThread),
+        "SCMP_ACT_KILL_PROCESS" => Ok(Action::KillProcess),
+        "SCMP_ACT_TRAP" => Ok(Action::Trap),
+        "SCMP_ACT_TRACE" => Ok(Action::Trace(errno_ret.unwrap_or(0))),
+        _ => Err(spec_error(format!("unsupported seccomp action {}", action)))
+    }
+}
+
+fn seccomp_comparator(arg: &SeccompArg) -> Result<Comparator, ErrorType> {
+    let op = match arg.op.as_str() {
+        "SCMP_CMP_NE" => Cmp::Ne,
+        "SCMP_CMP_LT" => Cmp::Lt,
+        "SCMP_CMP_LE" => Cmp::Le,
+        "SCMP_CMP_EQ" => Cmp::Eq,
+        "SCMP_CMP_GE" => Cmp::Ge,
+        "SCMP_CMP_GT" => Cmp::Gt,
+        "SCMP_CMP_MASKED_EQ" => Cmp::MaskedEq,
+        _ => return Err(spec_error(format!("unsupported seccomp operator {}", arg.op)))
+    };
+    Ok(Comparator::new(arg.index, op, arg.value, Some(arg.value_two)))
+}
+
+fn convert_seccomp(seccomp: &Seccomp) -> Result<SeccompProfile, ErrorType> {
+    let default_action = seccomp_action(&seccomp.default_action, seccomp.default_errno_ret)?;
+    let mut rules = Vec::new();
+    for entry in seccomp.syscalls.iter() {
+        let action = seccomp_action(&entry.action, entry.errno_ret)?;
+        let conditions = entry.args.iter().map(seccomp_comparator).collect::<Result<Vec<_>, _>>()?;
+        for name in entry.names.iter() {
+            match Syscall::from_name(name) {
+                Some(syscall) => rules.push(SeccompRule { syscall, action, conditions: conditions.clone() }),
+                // Profiles commonly list syscalls of every architecture
+                None => log::debug!("Skipping unknown syscall {} in seccomp profile", name)
+            }
+        }
+    }
+    Ok(SeccompProfile { default_action, rules })
+}
+
+fn convert_capabilities(capabilities: &Capabilities) -> Result<Vec<Cap>, ErrorType> {
+    let kept = capabilities.bounding.iter()
+        .map(|name| Cap::from_str(name).map_err(|_| spec_error(format!("unknown capability {}", name))))
+        .collect::<Result<Vec<Cap>, ErrorType>>()?;
+    Ok(Cap::iter().filter(|cap| !kept.contains(cap)).collect())
+}
+
+pub fn spec_to_config(spec: &Spec, bundle: &Path) -> Result<ContainerConfig, ErrorType> {
+    let process = spec.process.as_ref().ok_or_else(|| spec_error(String::from("missing process")))?;
+    if process.args.is_empty() { return Err(spec_error(String::from("process.args must not be empty"))); }
+    if process.terminal { log::warn!("process.terminal is not supported, using the inherited stdio"); }
+    let root = spec.root.as_ref().ok_or_else(|| spec_error(String::from("missing root")))?;
+    let mount_dir = bundle.join(&root.path).canonicalize().map_err(ErrorType::DirectoryError)?;
+    let to_cstrings = |strings: &[String]| strings.iter()
+        .map(|s| CString::new(s.as_str()).map_err(ErrorType::CStringError))
+        .collect::<Result<Vec<CString>, ErrorType>>();
+
+    let mut config = ContainerConfig::new(to_cstrings(&process.args)?, to_cstrings(&process.env)?, mount_dir);
+    config.readonly_rootfs = root.readonly;
+    config.mounts = spec.mounts.iter().map(|mnt| convert_mount(mnt, bundle)).collect();
+    config.workdir = PathBuf::from("/").join(&process.cwd);
+    config.uid = process.user.uid;
+    config.gid = process.user.gid;
+    config.hostname = spec.hostname.clone();
+    if let Some(capabilities) = &process.capabilities {
+        config.capabilities_drop = convert_capabilities(capabilities)?;
+    }
+    // Only what the bundle sets, the others being inherited from rucker
+    config.rlimits = process.rlimits.iter().map(|rlimit| {
+        let resource = parse_resource(&rlimit.rltype).ok_or_else(|| spec_error(format!("unknown rlimit type {}", rlimit.rltype)))?;
+        Ok(Rlimit { resource, soft: rlimit.soft, hard: rlimit.hard })
+    }).collect::<Result<Vec<Rlimit>, ErrorType>>()?;
+    // Without a linux section, keep rucker's defaults
+    let Some(linux) = &spec.linux else { return Ok(config) };
+    config.namespaces = CloneFlags::empty();
+    config.user_namespace = false;
+    for namespace in linux.namespaces.iter() {
+        let flag = namespace_flag(&namespace.nstype)?;
+        match (&namespace.path, flag) {
+            (Some(_), CloneFlags::CLONE_NEWUSER) => return Err(spec_error(String::from("joining a user namespace is not supported"))),
+            (Some(path), _) => config.join_namespaces.push((flag, path.clone())),
+            (None, CloneFlags::CLONE_NEWUSER) => config.user_namespace = true,
+            (None, _) => config.namespaces.insert(flag)
+        }
+    }
+    if !config.namespaces.contains(CloneFlags::CLONE_NEWNS) {
+        log::warn!("A mount namespace is required for pivot_root, creating one anyway");
+        config.namespaces.insert(CloneFlags::CLONE_NEWNS);
+    }
+    let to_mappings = |mappings: &[SpecIdMapping]| mappings.iter()
+        .map(|m| IdMapping { container_id: m.container_id, host_id: m.host_id, size: m.size }).collect::<Vec<_>>();
+    if !linux.uid_mappings.is_empty() { config.uid_mappings = to_mappings(&linux.uid_mappings); }
+    if !linux.gid_mappings.is_empty() { config.gid_mappings = to_mappings(&linux.gid_mappings); }
+    config.resources = linux.resources.as_ref().map(convert_resources).unwrap_or_else(Resources::unlimited);
+    if let Some(cgroups_path) = &linux.cgroups_path {
+        let path = parse_cgroup_path(cgroups_path)?;
+        config.cgroups_path = Some(if cgroups_path.starts_with('/') { path } else { hierarchy_path(&config.cgroup_parent, &path) });
+    }
+    config.seccomp = linux.seccomp.as_ref().map(convert_seccomp).transpose()?;
+    Ok(config)
+}
+
+pub fn create(opt: CreateOptions) -> Result<i32, ErrorType> {
+    validate_id(&opt.id)?;
+    if ContainerState::exists(&opt.id) { return Err(ErrorType::ContainerExistsError(opt.id)); }
+    let bundle = opt.bundle.canonicalize().map_err(ErrorType::DirectoryError)?;
+    let config = spec_to_config(&load_spec(&bundle)?, &bundle)?;
+
+    // The container process blocks on this fifo, right before execve, until `rucker start`
+    create_dir_all(state_dir(&opt.id)).map_err(ErrorType::StateError)?;
+    let fifo_path = exec_fifo_path(&opt.id);
+    mkfifo(&fifo_path, Mode::S_IRUSR | Mode::S_IWUSR).map_err(ErrorType::ExecFifoError)?;
+    let fifo = open(&fifo_path, OFlag::O_RDONLY | OFlag::O_NONBLOCK | OFlag::O_CLOEXEC, Mode::empty())
+        .map_err(ErrorType::ExecFifoError)?;
+
+    let mut container = Container::new(opt.id.clone(), config)?;
+    container.exec_fifo = Some(unsafe { OwnedFd::from_raw_fd(fifo) });
+    container.bundle = Some(bundle);
+    if let Err(err) = container.create() {
+        container.abort();
+        return Err(err);
+    }
+    let pid = container.child_proc.as_ref().unwrap().pid;
+    if let Some(pid_file) = opt.pid_file {
+        std::fs::write(pid_file, pid.to_string()).map_err(ErrorType::FileError)?;
+    }
+    log::info!("Container {} created, run `rucker start {}` to start it", opt.id, opt.id);
+    Ok(0)
+}
+
+pub fn start(id: String) -> Result<i32, ErrorType> {
+    let state = ContainerState::load(&id)?;
+    let status = state.status();
+    if status != Status::Created {
+        return Err(ErrorType::InvalidStateError(format!("container {} is {}, not created", id, status)));
+    }
+    let fifo_path = exec_fifo_path(&id);
+    let fifo = open(&fifo_path, OFlag::O_WRONLY | OFlag::O_NONBLOCK | OFlag::O_CLOEXEC, Mode::empty())
+        .map_err(ErrorType::ExecFifoError)?;
+    let fifo = unsafe { OwnedFd::from_raw_fd(fifo) };
+    write(&fifo, &[0]).map_err(ErrorType::ExecFifoError)?;
+    remove_file(&fifo_path).map_err(ErrorType::StateError)?;
+    log::info!("Container {} started", id);
+    Ok(0)
+}
+
+pub fn state(id: String) -> Result<i32, ErrorType> {
+    let state = ContainerState::load(&id)?;
+    let status = state.status();
+    let oci_state = OciState {
+        oci_version: OCI_VERSION,
+        id: &state.id,
+        status,
+        pid: if status == Status::Stopped { None } else { Some(state.pid) },
+        bundle: state.bundle.as_deref().unwrap_or(Path::new("")),
+        annotations: &state.annotations
+    };
+    println!("{}", serde_json::to_string_pretty(&oci_state).map_err(ErrorType::JsonError)?);
+    Ok(0)
+}
+
+pub fn kill(opt: KillOptions) -> Result<i32, ErrorType> {
+    let state = ContainerState::load(&opt.id)?;
+    let signal = parse_signal(&opt.signal)?;
+    if state.status() == Status::Stopped {
+        return Err(ErrorType::InvalidStateError(format!("container {} is not running", opt.id)));
+    }
+    if opt.all {
+        signal_cgroup(&state.cgroup, signal)?;
+    } else {
+        ChildProcess::of(Pid::from_raw(state.pid)).kill(signal)?;
+    }
+    log::info!("Sent {} to container {}", signal, opt.id);
+    Ok(0)
+}
+
+// Returns whether the container process exited within the timeout
+fn wait_for_exit(state: &ContainerState, timeout: Duration) -> bool {
+    let mut waited = Duration::ZERO;
+    while state.is_alive() {
+        if waited >= timeout { return false; }
+        sleep(POLL_INTERVAL);
+        waited += POLL_INTERVAL;
+    }
+    true
+}
+
+// SIGTERM first, SIGKILL to the whole cgroup if the container doesn't exit in time
+pub fn stop(opt: StopOptions) -> Result<i32, ErrorType> {
+    let state = ContainerState::load(&opt.id)?;
+    let status = state.status();
+    if status == Status::Stopped {
+        log::info!("Container {} is already stopped", opt.id);
+        return Ok(0);
+    }
+    // A created container is blocked before execve, so there is nothing to shut down gracefully
+    if status == Status::Running {
+        ChildProcess::of(Pid::from_raw(state.pid)).kill(Signal::SIGTERM)?;
+        if wait_for_exit(&state, Duration::from_secs(opt.time)) {
+            signal_cgroup(&state.cgroup, Signal::SIGKILL)?;
+            log::info!("Container {} stopped", opt.id);
+            return Ok(0);
+        }
+        log::warn!("Container {} did not stop within {}s, killing it", opt.id, opt.time);
+    }
+    ChildProcess::of(Pid::from_raw(state.pid)).kill(Signal::SIGKILL)?;
+    signal_cgroup(&state.cgroup, Signal::SIGKILL)?;
+    if !wait_for_exit(&state, DELETE_TIMEOUT) {
+        return Err(ErrorType::InvalidStateError(format!("container {} did not die after SIGKILL", opt.id)));
+    }
+    log::info!("Container {} killed", opt.id);
+    Ok(0)
+}
+
+pub fn delete(opt: DeleteOptions) -> Result<i32, ErrorType> {
+    let state = ContainerState::load(&opt.id)?;
+    let status = state.status();
+    if status != Status::Stopped {
+        if !opt.force {
+            return Err(ErrorType::InvalidStateError(format!("container {} is {}, stop it first or use --force", opt.id, status)));
+        }
+        ChildProcess::of(Pid::from_raw(state.pid)).kill(Signal::SIGKILL)?;
+        if !wait_for_exit(&state, DELETE_TIMEOUT) {
+            return Err(ErrorType::InvalidStateError(format!("container {} did not die after SIGKILL", opt.id)));
+        }
+    }
+    if let Err(err) = signal_cgroup(&state.cgroup, Signal::SIGKILL) { log::warn!("Failed to kill leftover processes of container {}: {:?}", opt.id, err); }
+    if let Err(err) = clean_cgroup(&state.cgroup) { log::warn!("Failed to clean cgroup of container {}: {:?}", opt.id, err); }
+    ContainerState::remove(&opt.id)?;
+    log::info!("Container {} deleted", opt.id);
+    Ok(0)
+}
@@ -0,0 +1,102 @@
+use crate::errors::{ErrorType, EXIT_SIGNAL_OFFSET};
+use crate::cgroup::hierarchy_path;
+use crate::cgroup_v2::CGROUP_ROOT;
+
+use cgroups_rs::hierarchies::{is_cgroup2_unified_mode, V1};
+use cgroups_rs::Controllers;
+use nix::sys::eventfd::{EfdFlags, EventFd};
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify};
+use nix::sys::signal::Signal;
+
+use std::fs::{read_to_string, write, File};
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+
+const V1_MEMORY_ROOT: &str = "/sys/fs/cgroup/memory";
+
+// Directory of the container's cgroup in the hierarchy of the memory controller
+fn memory_dir(cgroup: &Path) -> PathBuf {
+    let root = if is_cgroup2_unified_mode() {
+        PathBuf::from(CGROUP_ROOT)
+    } else {
+        V1::new().get_mount_point(Controllers::Mem).unwrap_or_else(|| PathBuf::from(V1_MEMORY_ROOT))
+    };
+    hierarchy_path(&root, cgroup)
+}
+
+// The file holding the oom_kill counter, memory.events on v2 and memory.oom_control on v1
+fn events_path(cgroup: &Path) -> PathBuf {
+    let file = if is_cgroup2_unified_mode() { "memory.events" } else { "memory.oom_control" };
+    memory_dir(cgroup).join(file)
+}
+
+// Processes of the cgroup the OOM killer killed so far, 0 once the cgroup is gone
+pub fn oom_kills(cgroup: &Path) -> u64 {
+    read_to_string(events_path(cgroup)).ok()
+        .and_then(|events| events.lines()
+            .filter_map(|line| line.split_once(' '))
+            .find(|(key, _)| *key == "oom_kill")
+            .and_then(|(_, value)| value.trim().parse().ok()))
+        .unwrap_or(0)
+}
+
+// Whether the container's init was killed by the OOM killer rather than crashing
+pub fn is_oom_killed(exit_code: i32, oom_kills: u64) -> bool {
+    oom_kills > 0 && exit_code == EXIT_SIGNAL_OFFSET + Signal::SIGKILL as i32
+}
+
+// Blocks until the kernel notifies an OOM event in the cgroup, or its removal. On v2, through
+// inotify on memory.events. On v1, through an eventfd registered in cgroup.event_control
+enum Notifier {
+    Inotify(Inotify),
+    // The registration is dropped along with memory.oom_control, so it is kept open
+    EventFd { event_fd: EventFd, _oom_control: File }
+}
+
+impl Notifier {
+    fn new(cgroup: &Path) -> Result<Notifier, ErrorType> {
+        let events = events_path(cgroup);
+        let error = |err: nix::errno::Errno| ErrorType::CgroupFileError(err.into(), events.clone());
+        if is_cgroup2_unified_mode() {
+            let inotify = Inotify::init(InitFlags::IN_CLOEXEC).map_err(error)?;
+            inotify.add_watch(&events, AddWatchFlags::IN_MODIFY).map_err(error)?;
+            return Ok(Notifier::Inotify(inotify));
+        }
+        let event_fd = EventFd::from_flags(EfdFlags::EFD_CLOEXEC).map_err(error)?;
+        let oom_control = File::open(&events).map_err(|err| ErrorType::CgroupFileError(err, events.clone()))?;
+        let event_control = memory_dir(cgroup).join("cgroup.event_control");
+        write(&event_control, format!("{} {}", event_fd.as_raw_fd(), oom_control.as_raw_fd()))
+            .map_err(|err| ErrorType::CgroupFileError(err, event_control))?;
+        Ok(Notifier::EventFd { event_fd, _oom_control: oom_control })
+    }
+
+    fn wait(&self) -> bool {
+        match self {
+            Notifier::Inotify(inotify) => inotify.read_events()
+                .is_ok_and(|events| events.iter().all(|event| !event.mask.contains(AddWatchFlags::IN_IGNORED))),
+            Notifier::EventFd { event_fd, .. } => event_fd.read().is_ok()
+        }
+    }
+}
+
+// Logs the OOM kills in the container's cgroup as they happen, from a thread of the
+// rucker process waiting on the container. The count is read again once it exited
+pub fn watch_oom(id: &str, cgroup: &Path) {
+    let notifier = match Notifier::new(cgroup) {
+        Ok(notifier) => notifier,
+        Err(err) => return log::warn!("Not watching container {} for OOM kills: {:?}", id, err)
+    };
+    let (id, cgroup) = (id.to_string(), cgroup.to_path_buf());
+    std::thread::spawn(move || {
+        let mut seen = oom_kills(&cgroup);
+        while notifier.wait() {
+            let kills = oom_kills(&cgroup);
+            if kills > seen {
+                log::warn!("The OOM killer killed {} process(es) of container {}", kills - seen, id);
+                seen = kills;
+            }
+            // v1 notifies the removal of the cgroup through the eventfd too
+            if !memory_dir(&cgroup).is_dir() { break; }
+        }
+    });
+}
@@ -0,0 +1,131 @@
+use crate::errors::ErrorType;
+use crate::nftables::{Batch, dnat, masquerade_to, NF_INET_LOCAL_OUT, NF_INET_POST_ROUTING, NF_INET_PRE_ROUTING, PRIORITY_DSTNAT, PRIORITY_SRCNAT};
+use crate::proxy::start_proxy;
+
+use serde::{Deserialize, Serialize};
+
+use std::fs::write;
+use std::net::Ipv4Addr;
+
+const LOCALHOST: Ipv4Addr = Ipv4Addr::new(127, 0, 0, 0);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    Tcp,
+    Udp
+}
+
+impl Protocol {
+    fn number(&self) -> u8 {
+        match self {
+            Protocol::Tcp => libc::IPPROTO_TCP as u8,
+            Protocol::Udp => libc::IPPROTO_UDP as u8
+        }
+    }
+}
+
+// A port of the host forwarded to a port of the container
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PublishedPort {
+    pub host_port: u16,
+    pub container_port: u16,
+    pub protocol: Protocol
+}
+
+impl std::fmt::Display for PublishedPort {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let protocol = match self.protocol { Protocol::Tcp => "tcp", Protocol::Udp => "udp" };
+        write!(f, "{}:{}/{}", self.host_port, self.container_port, protocol)
+    }
+}
+
+// "<host port>:<container port>", optionally followed by "/tcp" or "/udp"
+pub fn parse_publish(publish: &str) -> Result<PublishedPort, ErrorType> {
+    let invalid = || ErrorType::InvalidArgumentError(format!("invalid port {:?}, expected e.g. 8080:80 or 5353:53/udp", publish));
+    let (ports, protocol) = publish.split_once('/').unwrap_or((publish, "tcp"));
+    let protocol = match protocol {
+        "tcp" => Protocol::Tcp,
+        "udp" => Protocol::Udp,
+        _ => return Err(invalid())
+    };
+    let (host_port, container_port) = ports.split_once(':').ok_or_else(invalid)?;
+    let parse_port = |port: &str| port.parse::<u16>().ok().filter(|&port| port > 0).ok_or_else(invalid);
+    Ok(PublishedPort { host_port: parse_port(host_port)?, container_port: parse_port(container_port)?, protocol })
+}
+
+pub fn build_ports(publish: &[String]) -> Result<Vec<PublishedPort>, ErrorType> {
+    let mut ports: Vec<PublishedPort> = Vec::new();
+    for publish in publish.iter() {
+        let port = parse_publish(publish)?;
+        if ports.iter().any(|other| other.host_port == port.host_port && other.protocol == port.protocol) {
+            return Err(ErrorType::InvalidArgumentError(format!("port {} is published twice", port.host_port)));
+        }
+        ports.push(port);
+    }
+    Ok(ports)
+}
+
+// Each container with published ports gets a table of its own, deleted along with its rules
+fn nat_table(id: &str) -> String {
+    format!("rucker-{}", id)
+}
+
+// Connections to the ports on any address of the host are DNATed to the container, whether they come
+// from outside (prerouting) or from the host itself (output). Those to 127.0.0.1 would reach the
+// container with a source it can't answer, so they are masqueraded too, which takes the bridge to
+// route loopback addresses. What comes from the bridge to them is dropped, see setup_bridge
+fn add_dnat_rules(id: &str, address: Ipv4Addr, bridge: &str, ports: &[PublishedPort]) -> Result<(), ErrorType> {
+    let table = nat_table(id);
+    let mut batch = Batch::new();
+    batch.add_table(&table);
+    batch.add_nat_chain(&table, "prerouting", NF_INET_PRE_ROUTING, PRIORITY_DSTNAT);
+    batch.add_nat_chain(&table, "output", NF_INET_LOCAL_OUT, PRIORITY_DSTNAT);
+    batch.add_nat_chain(&table, "postrouting", NF_INET_POST_ROUTING, PRIORITY_SRCNAT);
+    for port in ports.iter() {
+        let rule = dnat(port.protocol.number(), port.host_port, address, port.container_port);
+        batch.add_rule(&table, "prerouting", &rule);
+        batch.add_rule(&table, "output", &rule);
+    }
+    batch.add_rule(&table, "postrouting", &masquerade_to(LOCALHOST, 8, address));
+    batch.commit(&format!("publish the ports of container {}", id))?;
+    let route_localnet = format!("/proc/sys/net/ipv4/conf/{}/route_localnet", bridge);
+    if let Err(err) = write(&route_localnet, "1") {
+        // Left in place, the rules would take the ports from the proxies publish_ports falls back to
+        if let Err(err) = unpublish_ports(id) { log::warn!("Failed to remove the DNAT rules of container {}: {:?}", id, err); }
+        return Err(ErrorType::FileError(err));
+    }
+    Ok(())
+}
+
+// Falls back to proxying the ports from rucker itself where nftables can't be used, e.g. without
+// the nf_tables module, as setup_bridge may have found out already: DNAT to 127.0.0.1 is only
+// safe along with the rules of the bridge
+pub fn publish_ports(id: &str, address: Ipv4Addr, bridge: &str, ports: &[PublishedPort], bridge_rules: bool) -> Result<(), ErrorType> {
+    if ports.is_empty() { return Ok(()); }
+    let published = ports.iter().map(PublishedPort::to_string).collect::<Vec<String>>().join(", ");
+    let dnat = if bridge_rules {
+        add_dnat_rules(id, address, bridge, ports)
+    } else {
+        Err(ErrorType::InvalidStateError(format!("the rules of bridge {} are not in place", bridge)))
+    };
+    match dnat {
+        Ok(()) => log::info!("Published ports {} of container {}", published, id),
+        Err(err) => {
+            log::warn!("Failed to set up DNAT rules for the published ports, proxying them instead: {:?}", err);
+            for port in ports.iter() { start_proxy(port, address)?; }
+            log::info!("Proxying ports {} of container {}", published, id);
+        }
+    }
+    Ok(())
+}
+
+// The proxies go away with the rucker process waiting on the container
+pub fn unpublish_ports(id: &str) -> Result<(), ErrorType> {
+    let mut batch = Batch::new();
+    batch.delete_table(&nat_table(id));
+    match batch.commit(&format!("unpublish the ports of container {}", id)) {
+        Err(ErrorType::NetlinkError(err, _)) if err.raw_os_error() == Some(libc::ENOENT) => Ok(()),
+        result => result
+    }
+}
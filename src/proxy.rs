@@ -0,0 +1,109 @@
+use crate::errors::ErrorType;
+use crate::ports::{Protocol, PublishedPort};
+
+use std::collections::HashMap;
+use std::io::copy;
+use std::net::{Ipv4Addr, Shutdown, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::thread::spawn;
+use std::time::Duration;
+
+// A UDP client is forgotten once the container didn't answer it for that long
+const UDP_TIMEOUT: Duration = Duration::from_secs(60);
+const UDP_BUFFER_SIZE: usize = 65536;
+
+// Copies one direction of a connection, then passes the end of it on
+fn relay(from: TcpStream, to: TcpStream) {
+    if let Err(err) = copy(&mut &from, &mut &to) { log::debug!("Proxied connection broke: {:?}", err); }
+    let _ = to.shutdown(Shutdown::Write);
+}
+
+fn proxy_tcp(listener: TcpListener, upstream: SocketAddr) {
+    for client in listener.incoming() {
+        let client = match client {
+            Ok(client) => client,
+            Err(err) => {
+                log::debug!("Failed to accept a connection to proxy: {:?}", err);
+                continue;
+            }
+        };
+        spawn(move || {
+            let server = match TcpStream::connect(upstream) {
+                Ok(server) => server,
+                Err(err) => return log::warn!("Failed to proxy a connection to {}: {:?}", upstream, err)
+            };
+            let (client_reader, server_writer) = match (client.try_clone(), server.try_clone()) {
+                (Ok(client_reader), Ok(server_writer)) => (client_reader, server_writer),
+                _ => return
+            };
+            let upload = spawn(move || relay(client_reader, server_writer));
+            relay(server, client);
+            let _ = upload.join();
+        });
+    }
+}
+
+// Relays what the container answers to a UDP client, until it stops answering
+fn proxy_udp_replies(socket: Arc<UdpSocket>, session: Arc<UdpSocket>, client: SocketAddr, sessions: Arc<Mutex<HashMap<SocketAddr, Arc<UdpSocket>>>>) {
+    let mut buffer = vec![0; UDP_BUFFER_SIZE];
+    while let Ok(len) = session.recv(&mut buffer) {
+        if let Err(err) = socket.send_to(&buffer[..len], client) { log::debug!("Failed to proxy a reply to {}: {:?}", client, err); }
+    }
+    sessions.lock().unwrap().remove(&client);
+}
+
+// Each client gets a socket of its own towards the container, so that replies find their way back
+fn proxy_udp(socket: UdpSocket, upstream: SocketAddr) {
+    let socket = Arc::new(socket);
+    let sessions: Arc<Mutex<HashMap<SocketAddr, Arc<UdpSocket>>>> = Arc::new(Mutex::new(HashMap::new()));
+    let mut buffer = vec![0; UDP_BUFFER_SIZE];
+    loop {
+        let (len, client) = match socket.recv_from(&mut buffer) {
+            Ok(received) => received,
+            Err(err) => {
+                log::debug!("Failed to receive a datagram to proxy: {:?}", err);
+                continue;
+            }
+        };
+        let existing = sessions.lock().unwrap().get(&client).cloned();
+        let session = match existing {
+            Some(session) => session,
+            None => {
+                let session = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+                    .and_then(|session| session.connect(upstream).map(|_| session))
+                    .and_then(|session| session.set_read_timeout(Some(UDP_TIMEOUT)).map(|_| session));
+                let session = match session {
+                    Ok(session) => Arc::new(session),
+                    Err(err) => {
+                        log::warn!("Failed to proxy a datagram to {}: {:?}", upstream, err);
+                        continue;
+                    }
+                };
+                sessions.lock().unwrap().insert(client, session.clone());
+                let (socket, replies, sessions) = (socket.clone(), session.clone(), sessions.clone());
+                spawn(move || proxy_udp_replies(socket, replies, client, sessions));
+                session
+            }
+        };
+        if let Err(err) = session.send(&buffer[..len]) { log::debug!("Failed to proxy a datagram to {}: {:?}", upstream, err); }
+    }
+}
+
+// Forwards the port of the host to the container from threads of the rucker process waiting on it.
+// The port is bound right away, so that one already in use fails the container setup
+pub fn start_proxy(port: &PublishedPort, address: Ipv4Addr) -> Result<(), ErrorType> {
+    let error = |err| ErrorType::ProxyError(err, port.to_string());
+    let host = SocketAddr::from((Ipv4Addr::UNSPECIFIED, port.host_port));
+    let upstream = SocketAddr::from((address, port.container_port));
+    match port.protocol {
+        Protocol::Tcp => {
+            let listener = TcpListener::bind(host).map_err(error)?;
+            spawn(move || proxy_tcp(listener, upstream));
+        },
+        Protocol::Udp => {
+            let socket = UdpSocket::bind(host).map_err(error)?;
+            spawn(move || proxy_udp(socket, upstream));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,61 @@
+use crate::errors::ErrorType;
+use crate::state::{ContainerState, Status, now};
+use crate::oom::{is_oom_killed, oom_kills};
+
+use serde::Serialize;
+
+// The container state as printed by `rucker inspect`
+#[derive(Serialize)]
+struct Inspection<'a> {
+    #[serde(flatten)]
+    state: &'a ContainerState,
+    status: Status,
+    // Tells a container the OOM killer killed apart from one that crashed
+    oom_killed: bool
+}
+
+// "42 seconds ago", "3 minutes ago"...
+fn format_age(created: u64) -> String {
+    let secs = now().saturating_sub(created);
+    let (value, unit) = match secs {
+        0..=59 => (secs, "second"),
+        60..=3599 => (secs / 60, "minute"),
+        3600..=86399 => (secs / 3600, "hour"),
+        _ => (secs / 86400, "day")
+    };
+    format!("{} {}{} ago", value, unit, if value == 1 { "" } else { "s" })
+}
+
+pub fn ps() -> Result<i32, ErrorType> {
+    let states = ContainerState::list()?;
+    println!("{:<16} {:<8} {:<12} {:<16} COMMAND", "CONTAINER ID", "PID", "STATUS", "CREATED");
+    let mut stale = 0;
+    for state in states.iter() {
+        let status = state.status();
+        // Only `rucker create` and detached containers leave a stopped container behind
+        // on purpose, anything else lost its rucker process before it could clean up
+        if status == Status::Stopped && state.bundle.is_none() && state.exit_code.is_none() { stale += 1; }
+        let pid = if status == Status::Stopped { "-".to_string() } else { state.pid.to_string() };
+        let status = match state.exit_code {
+            Some(exit_code) if status == Status::Stopped => format!("{} ({})", status, exit_code),
+            _ => status.to_string()
+        };
+        println!("{:<16} {:<8} {:<12} {:<16} {}", state.id, pid, status, format_age(state.created), state.command.join(" "));
+    }
+    if stale > 0 {
+        log::warn!("{} stale container(s) whose process is gone, remove them with `rucker delete <id>`", stale);
+    }
+    Ok(0)
+}
+
+pub fn inspect(id: String) -> Result<i32, ErrorType> {
+    let mut state = ContainerState::load(&id)?;
+    let status = state.status();
+    // Only recorded by the supervisor of a detached container once it exited, the cgroup of
+    // other containers being left until `rucker delete`
+    if state.finished.is_none() { state.oom_kills = oom_kills(&state.cgroup); }
+    let oom_killed = state.exit_code.is_some_and(|exit_code| is_oom_killed(exit_code, state.oom_kills));
+    let inspection = Inspection { state: &state, status, oom_killed };
+    println!("{}", serde_json::to_string_pretty(&inspection).map_err(ErrorType::JsonError)?);
+    Ok(0)
+}
@@ -0,0 +1,70 @@
+use crate::errors::ErrorType;
+
+use rlimit::{Resource, INFINITY};
+
+// Applied unless overridden with --ulimit, like the default of `runc spec`
+pub const DEFAULT_RLIMITS: [Rlimit; 1] = [
+    Rlimit { resource: Resource::NOFILE, soft: 1024, hard: 1024 }
+];
+
+#[derive(Clone, Copy, Debug)]
+pub struct Rlimit {
+    pub resource: Resource,
+    pub soft: u64,
+    pub hard: u64
+}
+
+fn invalid_ulimit(ulimit: &str) -> ErrorType {
+    ErrorType::InvalidArgumentError(format!("invalid ulimit {:?}, expected e.g. nofile=1024:4096", ulimit))
+}
+
+// "nofile", "NOFILE" or "RLIMIT_NOFILE"
+pub fn parse_resource(name: &str) -> Option<Resource> {
+    let name = name.to_uppercase();
+    let name = if name.starts_with("RLIMIT_") { name } else { format!("RLIMIT_{}", name) };
+    name.parse::<Resource>().ok().filter(|resource| resource.is_supported())
+}
+
+fn parse_value(value: &str) -> Option<u64> {
+    match value {
+        "unlimited" | "-1" => Some(INFINITY),
+        value => value.parse().ok()
+    }
+}
+
+// "<name>=<soft>:<hard>", or "<name>=<limit>" for both, "unlimited" lifting the limit
+pub fn parse_ulimit(ulimit: &str) -> Result<Rlimit, ErrorType> {
+    let (name, values) = ulimit.split_once('=').ok_or_else(|| invalid_ulimit(ulimit))?;
+    let resource = parse_resource(name)
+        .ok_or_else(|| ErrorType::InvalidArgumentError(format!("unknown ulimit {:?}, expected e.g. nofile, nproc or core", name)))?;
+    let (soft, hard) = values.split_once(':').unwrap_or((values, values));
+    let (soft, hard) = match (parse_value(soft), parse_value(hard)) {
+        (Some(soft), Some(hard)) => (soft, hard),
+        _ => return Err(invalid_ulimit(ulimit))
+    };
+    if soft > hard {
+        return Err(ErrorType::InvalidArgumentError(format!("the soft limit of ulimit {:?} is above its hard limit", ulimit)));
+    }
+    Ok(Rlimit { resource, soft, hard })
+}
+
+// The defaults, each replaced by the one given for the same resource
+pub fn build_rlimits(ulimits: &[String]) -> Result<Vec<Rlimit>, ErrorType> {
+    let mut rlimits = DEFAULT_RLIMITS.to_vec();
+    for ulimit in ulimits.iter() {
+        let rlimit = parse_ulimit(ulimit)?;
+        rlimits.retain(|existing| existing.resource != rlimit.resource);
+        rlimits.push(rlimit);
+    }
+    Ok(rlimits)
+}
+
+// Called in the child while it still has CAP_SYS_RESOURCE in the host's user namespace,
+// which raising a hard limit takes
+pub fn set_rlimits(rlimits: &[Rlimit]) -> Result<(), ErrorType> {
+    for rlimit in rlimits.iter() {
+        rlimit.resource.set(rlimit.soft, rlimit.hard).map_err(ErrorType::RlimitError)?;
+        log::debug!("Set {} to {}:{}", rlimit.resource.as_name(), rlimit.soft, rlimit.hard);
+    }
+    Ok(())
+}
@@ -0,0 +1,204 @@
+use crate::errors::ErrorType;
+use crate::childproc::ChildProcess;
+use crate::config::ContainerConfig;
+use crate::ports::PublishedPort;
+
+use nix::unistd::Pid;
+use serde::{Deserialize, Serialize};
+
+use std::collections::BTreeMap;
+use std::net::Ipv4Addr;
+use std::fs::{create_dir_all, read_dir, read_to_string, remove_dir_all, rename, write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const STATE_DIR: &str = "/run/rucker";
+const STATE_FILE: &str = "state.json";
+const EXEC_FIFO: &str = "exec.fifo";
+const LOG_FILE: &str = "container.log";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Created,
+    Running,
+    Stopped
+}
+
+impl std::fmt::Display for Status {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Status::Created => write!(f, "created"),
+            Status::Running => write!(f, "running"),
+            Status::Stopped => write!(f, "stopped")
+        }
+    }
+}
+
+// What rucker remembers about a container between invocations,
+// stored as /run/rucker/<id>/state.json
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ContainerState {
+    pub id: String,
+    pub pid: i32,
+    // Start time of the process, see ChildProcess::start_time
+    #[serde(default)]
+    pub start_time: Option<u64>,
+    // Only set for containers created from an OCI bundle
+    pub bundle: Option<PathBuf>,
+    pub rootfs: PathBuf,
+    #[serde(default)]
+    pub command: Vec<String>,
+    // What `rucker exec` needs to run a command like the container's one
+    #[serde(default)]
+    pub uid: u32,
+    #[serde(default)]
+    pub gid: u32,
+    #[serde(default)]
+    pub workdir: PathBuf,
+    #[serde(default)]
+    pub cgroup: PathBuf,
+    // Address on the bridge, for a container with a network
+    #[serde(default)]
+    pub ip: Option<Ipv4Addr>,
+    #[serde(default)]
+    pub ports: Vec<PublishedPort>,
+    // Seconds since the Unix epoch
+    pub created: u64,
+    // Recorded by the supervisor of a detached container when it exits
+    #[serde(default)]
+    pub finished: Option<u64>,
+    #[serde(default)]
+    pub exit_code: Option<i32>,
+    // Processes of the container the OOM killer killed, its init or others
+    #[serde(default)]
+    pub oom_kills: u64,
+    #[serde(default)]
+    pub annotations: BTreeMap<String, String>
+}
+
+// Container IDs end up in paths, so only allow a conservative set of characters
+pub fn validate_id(id: &str) -> Result<(), ErrorType> {
+    let valid = !id.is_empty() && !id.starts_with('.')
+        && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.');
+    if valid { Ok(()) } else { Err(ErrorType::InvalidArgumentError(format!("invalid container ID: {:?}", id))) }
+}
+
+pub fn state_dir(id: &str) -> PathBuf {
+    Path::new(STATE_DIR).join(id)
+}
+
+pub fn exec_fifo_path(id: &str) -> PathBuf {
+    state_dir(id).join(EXEC_FIFO)
+}
+
+pub fn log_path(id: &str) -> PathBuf {
+    state_dir(id).join(LOG_FILE)
+}
+
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+impl ContainerState {
+    pub fn new(id: &str, pid: Pid, bundle: Option<PathBuf>, config: &ContainerConfig) -> ContainerState {
+        ContainerState {
+            id: id.to_string(),
+            pid: pid.as_raw(),
+            start_time: ChildProcess::of(pid).start_time(),
+            bundle,
+            rootfs: config.mount_dir.clone(),
+            command: config.argv.iter().map(|arg| arg.to_string_lossy().into_owned()).collect(),
+            uid: config.uid,
+            gid: config.gid,
+            workdir: config.workdir.clone(),
+            cgroup: config.cgroup_path(id),
+            ip: None,
+            ports: config.network.as_ref().map(|network| network.ports.clone()).unwrap_or_default(),
+            created: now(),
+            finished: None,
+            exit_code: None,
+            oom_kills: 0,
+            annotations: BTreeMap::new()
+        }
+    }
+
+    pub fn exists(id: &str) -> bool {
+        state_dir(id).join(STATE_FILE).exists()
+    }
+
+    pub fn load(id: &str) -> Result<ContainerState, ErrorType> {
+        validate_id(id)?;
+        let path = state_dir(id).join(STATE_FILE);
+        if !path.exists() { return Err(ErrorType::ContainerNotFoundError(id.to_string())); }
+        let content = read_to_string(&path).map_err(ErrorType::StateError)?;
+        serde_json::from_str(&content).map_err(ErrorType::JsonError)
+    }
+
+    // Every container with a state directory, oldest first
+    pub fn list() -> Result<Vec<ContainerState>, ErrorType> {
+        let entries = match read_dir(STATE_DIR) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(ErrorType::StateError(err))
+        };
+        let mut states = Vec::new();
+        for entry in entries {
+            let id = entry.map_err(ErrorType::StateError)?.file_name().to_string_lossy().into_owned();
+            // A container being created or deleted may not have a state file
+            if !ContainerState::exists(&id) { continue; }
+            match ContainerState::load(&id) {
+                Ok(state) => states.push(state),
+                Err(err) => log::warn!("Skipping container {} with unreadable state: {:?}", id, err)
+            }
+        }
+        states.sort_by_key(|state| state.created);
+        Ok(states)
+    }
+
+    // Written to a temporary file first so that readers never see a partial state
+    pub fn save(&self) -> Result<(), ErrorType> {
+        create_dir_all(state_dir(&self.id)).map_err(ErrorType::StateError)?;
+        self.update()
+    }
+
+    // Saves the state of a container that may have been deleted since it was loaded, without
+    // bringing its directory back
+    pub fn update(&self) -> Result<(), ErrorType> {
+        let dir = state_dir(&self.id);
+        let not_found = |err: std::io::Error| match err.kind() {
+            std::io::ErrorKind::NotFound => ErrorType::ContainerNotFoundError(self.id.clone()),
+            _ => ErrorType::StateError(err)
+        };
+        let content = serde_json::to_string_pretty(self).map_err(ErrorType::JsonError)?;
+        let tmp_path = dir.join(format!(".{}.tmp", STATE_FILE));
+        write(&tmp_path, content).map_err(not_found)?;
+        rename(&tmp_path, dir.join(STATE_FILE)).map_err(not_found)?;
+        log::debug!("Saved state of container {}", self.id);
+        Ok(())
+    }
+
+    pub fn remove(id: &str) -> Result<(), ErrorType> {
+        let dir = state_dir(id);
+        if dir.exists() { remove_dir_all(&dir).map_err(ErrorType::StateError)?; }
+        log::debug!("Removed state of container {}", id);
+        Ok(())
+    }
+
+    // False once the process is gone, even if another one has since been given its pid
+    pub fn is_alive(&self) -> bool {
+        let child_proc = ChildProcess::of(Pid::from_raw(self.pid));
+        child_proc.is_alive() && (self.start_time.is_none() || child_proc.start_time() == self.start_time)
+    }
+
+    pub fn status(&self) -> Status {
+        if !self.is_alive() {
+            Status::Stopped
+        } else if exec_fifo_path(&self.id).exists() {
+            // The process is set up and blocked until `rucker start` opens the fifo
+            Status::Created
+        } else {
+            Status::Running
+        }
+    }
+}
@@ -0,0 +1,173 @@
+use crate::errors::ErrorType;
+use crate::state::{ContainerState, Status};
+use crate::cgroup::relative_path;
+use crate::oom::oom_kills;
+use crate::StatsOptions;
+
+use cgroups_rs::blkio::BlkIoController;
+use cgroups_rs::cpu::CpuController;
+use cgroups_rs::cpuacct::CpuAcctController;
+use cgroups_rs::hierarchies::auto;
+use cgroups_rs::memory::MemController;
+use cgroups_rs::pid::PidController;
+use cgroups_rs::{Cgroup, Controller};
+use serde::Serialize;
+
+use std::collections::HashMap;
+use std::io::{stdout, Write};
+use std::thread::sleep;
+use std::time::{Duration, Instant, SystemTime};
+
+const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+// Memory limits from there on mean no limit, e.g. the v1 default of 0x7ffffffffffff000
+const UNLIMITED_MEMORY: i64 = 1 << 62;
+// Moves the cursor home and clears the screen, for the live table
+const CLEAR_SCREEN: &str = "\x1b[H\x1b[2J";
+
+// What a container consumes, as read from its cgroup
+#[derive(Serialize)]
+struct Stats {
+    id: String,
+    // RFC 3339, when the cgroup was read
+    time: String,
+    memory_usage: u64,
+    // None without a limit
+    memory_limit: Option<u64>,
+    memory_peak: u64,
+    oom_kills: u64,
+    // In nanoseconds
+    cpu_usage: u64,
+    // Of one CPU, since the previous reading
+    cpu_percent: Option<f64>,
+    throttled_periods: u64,
+    // In nanoseconds
+    throttled_time: u64,
+    pids: u64,
+    io_read_bytes: u64,
+    io_write_bytes: u64,
+    #[serde(skip)]
+    read_at: Option<Instant>
+}
+
+// A value of a flat keyed file like cpu.stat
+fn keyed_value(content: &str, key: &str) -> Option<u64> {
+    content.lines()
+        .filter_map(|line| line.split_once(' '))
+        .find(|(name, _)| *name == key)
+        .and_then(|(_, value)| value.trim().parse().ok())
+}
+
+fn read_stats(state: &ContainerState) -> Stats {
+    let cgroup = Cgroup::load(auto(), relative_path(&state.cgroup));
+    let mut stats = Stats {
+        id: state.id.clone(),
+        time: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+        memory_usage: 0, memory_limit: None, memory_peak: 0, oom_kills: 0,
+        cpu_usage: 0, cpu_percent: None, throttled_periods: 0, throttled_time: 0,
+        pids: 0, io_read_bytes: 0, io_write_bytes: 0,
+        read_at: Some(Instant::now())
+    };
+    // The v2 reading of cgroups-rs panics on a cgroup the memory controller is not enabled in
+    if let Some(memory) = cgroup.controller_of::<MemController>().filter(|memory| !cgroup.v2() || memory.path().join("memory.max").exists()) {
+        let memory_stat = memory.memory_stat();
+        stats.memory_usage = memory_stat.usage_in_bytes;
+        stats.memory_peak = memory_stat.max_usage_in_bytes;
+        let limit = memory_stat.limit_in_bytes;
+        stats.memory_limit = if limit > 0 && limit < UNLIMITED_MEMORY { Some(limit as u64) } else { None };
+        stats.oom_kills = oom_kills(&state.cgroup);
+    }
+    // cpu.stat counts in microseconds on v2, where it also holds the usage, and in nanoseconds on v1
+    if let Some(cpu) = cgroup.controller_of::<CpuController>() {
+        let cpu_stat = cpu.cpu().stat;
+        stats.throttled_periods = keyed_value(&cpu_stat, "nr_throttled").unwrap_or(0);
+        if cgroup.v2() {
+            stats.cpu_usage = keyed_value(&cpu_stat, "usage_usec").unwrap_or(0) * 1000;
+            stats.throttled_time = keyed_value(&cpu_stat, "throttled_usec").unwrap_or(0) * 1000;
+        } else {
+            stats.throttled_time = keyed_value(&cpu_stat, "throttled_time").unwrap_or(0);
+        }
+    }
+    if let Some(cpuacct) = cgroup.controller_of::<CpuAcctController>() {
+        stats.cpu_usage = cpuacct.cpuacct().usage;
+    }
+    if let Some(pids) = cgroup.controller_of::<PidController>() {
+        stats.pids = pids.get_pid_current().unwrap_or(0);
+    }
+    if let Some(blkio) = cgroup.controller_of::<BlkIoController>() {
+        let blkio = blkio.blkio();
+        if cgroup.v2() {
+            stats.io_read_bytes = blkio.io_stat.iter().map(|device| device.rbytes).sum();
+            stats.io_write_bytes = blkio.io_stat.iter().map(|device| device.wbytes).sum();
+        } else {
+            stats.io_read_bytes = blkio.throttle.io_service_bytes_recursive.iter().map(|device| device.read).sum();
+            stats.io_write_bytes = blkio.throttle.io_service_bytes_recursive.iter().map(|device| device.write).sum();
+        }
+    }
+    stats
+}
+
+// "512B", "12.3MiB"...
+fn format_size(size: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = size as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 { format!("{}B", size) } else { format!("{:.1}{}", value, UNITS[unit]) }
+}
+
+fn print_table(stats: &[Stats]) {
+    println!("{:<16} {:<8} {:<22} {:<8} {:<6} {:<10} {:<20} PIDS",
+        "CONTAINER ID", "CPU %", "MEM USAGE / LIMIT", "MEM PEAK", "OOM", "THROTTLED", "BLOCK I/O");
+    for stats in stats.iter() {
+        let cpu = stats.cpu_percent.map_or("-".to_string(), |percent| format!("{:.2}%", percent));
+        let memory = format!("{} / {}", format_size(stats.memory_usage), stats.memory_limit.map_or("-".to_string(), format_size));
+        let io = format!("{} / {}", format_size(stats.io_read_bytes), format_size(stats.io_write_bytes));
+        println!("{:<16} {:<8} {:<22} {:<8} {:<6} {:<10} {:<20} {}",
+            stats.id, cpu, memory, format_size(stats.memory_peak), stats.oom_kills, stats.throttled_periods, io, stats.pids);
+    }
+}
+
+// The given containers, or every one, as long as they run
+fn running_containers(ids: &[String]) -> Result<Vec<ContainerState>, ErrorType> {
+    let states = if ids.is_empty() {
+        ContainerState::list()?
+    } else {
+        ids.iter().map(|id| ContainerState::load(id)).collect::<Result<Vec<ContainerState>, ErrorType>>()?
+    };
+    Ok(states.into_iter().filter(|state| state.status() == Status::Running).collect())
+}
+
+// Reads the cgroups every REFRESH_INTERVAL, the CPU usage being the difference between two readings
+pub fn stats(opt: StatsOptions) -> Result<i32, ErrorType> {
+    let mut previous: HashMap<String, Stats> = running_containers(&opt.ids)?.iter()
+        .map(|state| (state.id.clone(), read_stats(state)))
+        .collect();
+    loop {
+        sleep(REFRESH_INTERVAL);
+        let states = running_containers(&opt.ids)?;
+        if !opt.ids.is_empty() && states.is_empty() {
+            log::info!("No container left running");
+            return Ok(0);
+        }
+        let mut current: Vec<Stats> = states.iter().map(read_stats).collect();
+        for stats in current.iter_mut() {
+            let Some(before) = previous.get(&stats.id) else { continue; };
+            if let (Some(now), Some(then)) = (stats.read_at, before.read_at) {
+                let elapsed = now.duration_since(then).as_nanos() as f64;
+                stats.cpu_percent = Some(stats.cpu_usage.saturating_sub(before.cpu_usage) as f64 / elapsed * 100.0);
+            }
+        }
+        if opt.json {
+            for stats in current.iter() { println!("{}", serde_json::to_string(stats).map_err(ErrorType::JsonError)?); }
+        } else {
+            if !opt.no_stream { print!("{}", CLEAR_SCREEN); }
+            print_table(&current);
+        }
+        stdout().flush().map_err(ErrorType::FileError)?;
+        if opt.no_stream { return Ok(0); }
+        previous = current.into_iter().map(|stats| (stats.id.clone(), stats)).collect();
+    }
+}
@@ -1,19 +1,24 @@
 use libc::TIOCSTI;
 use nix::sys::stat::Mode;
 use nix::sched::CloneFlags;
-use syscallz::Syscall;
+use syscallz::{Action, Cmp, Comparator, Syscall};
 
 pub const S_ISUID: u64 = Mode::S_ISUID.bits() as u64;
 pub const S_ISGID: u64 = Mode::S_ISGID.bits() as u64;
 pub const CLONE_NEWUSER: u64 = CloneFlags::CLONE_NEWUSER.bits() as u64;
 
-pub const SYSCALLS_CONDITIONALLY_REFUSED: [(Syscall, u32, u64); 9] = [
-    (Syscall::chmod, 1, S_ISUID), (Syscall::chmod, 1, S_ISGID),
-    (Syscall::fchmod, 1, S_ISUID), (Syscall::fchmod, 1, S_ISGID),
-    (Syscall::fchmodat, 2, S_ISUID), (Syscall::fchmodat, 2, S_ISGID),
-    (Syscall::unshare, 0, CLONE_NEWUSER),
-    (Syscall::clone, 0, CLONE_NEWUSER),
-    (Syscall::ioctl, 1, TIOCSTI),
+// The ioctl request is an int: only its lower 32 bits are compared, and all of them,
+// as a mask of TIOCSTI's own bits would also match e.g. TIOCGWINSZ
+const IOCTL_REQUEST_MASK: u64 = u32::MAX as u64;
+
+// (syscall, argument index, mask, value): refused when the argument masked equals the value
+pub const SYSCALLS_CONDITIONALLY_REFUSED: [(Syscall, u32, u64, u64); 9] = [
+    (Syscall::chmod, 1, S_ISUID, S_ISUID), (Syscall::chmod, 1, S_ISGID, S_ISGID),
+    (Syscall::fchmod, 1, S_ISUID, S_ISUID), (Syscall::fchmod, 1, S_ISGID, S_ISGID),
+    (Syscall::fchmodat, 2, S_ISUID, S_ISUID), (Syscall::fchmodat, 2, S_ISGID, S_ISGID),
+    (Syscall::unshare, 0, CLONE_NEWUSER, CLONE_NEWUSER),
+    (Syscall::clone, 0, CLONE_NEWUSER, CLONE_NEWUSER),
+    (Syscall::ioctl, 1, IOCTL_REQUEST_MASK, TIOCSTI),
 ];
 
 pub const SYSCALLS_REFUSED: [Syscall; 9] = [
@@ -27,3 +32,31 @@ pub const SYSCALLS_REFUSED: [Syscall; 9] = [
     Syscall::userfaultfd,
     Syscall::perf_event_open
 ];
+
+const EPERM: u16 = 1;
+
+pub struct SeccompRule {
+    pub syscall: Syscall,
+    pub action: Action,
+    // All of them must match for the rule to apply
+    pub conditions: Vec<Comparator>
+}
+
+pub struct SeccompProfile {
+    pub default_action: Action,
+    pub rules: Vec<SeccompRule>
+}
+
+impl Default for SeccompProfile {
+    fn default() -> SeccompProfile {
+        let refused = SYSCALLS_REFUSED.iter()
+            .map(|&syscall| SeccompRule { syscall, action: Action::Errno(EPERM), conditions: vec![] });
+        let conditionally_refused = SYSCALLS_CONDITIONALLY_REFUSED.iter()
+            .map(|&(syscall, ind, mask, value)| SeccompRule {
+                syscall,
+                action: Action::Errno(EPERM),
+                conditions: vec![Comparator::new(ind, Cmp::MaskedEq, mask, Some(value))]
+            });
+        SeccompProfile { default_action: Action::Allow, rules: refused.chain(conditionally_refused).collect() }
+    }
+}
@@ -0,0 +1,157 @@
+use crate::errors::ErrorType;
+use crate::childproc::{ChildProcess, forwarded_signals};
+
+use nix::errno::Errno;
+use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+use nix::poll::{poll, PollFd, PollFlags, PollTimeout};
+use nix::pty::{openpty, Winsize};
+use nix::sys::signal::{SigSet, Signal};
+use nix::sys::signalfd::{SfdFlags, SignalFd};
+use nix::sys::termios::{cfmakeraw, tcgetattr, tcsetattr, SetArg, Termios};
+use nix::unistd::{dup2, isatty, read, setsid, write};
+
+use std::io::{stdin, stdout, Write};
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, OwnedFd};
+
+const BUFFER_SIZE: usize = 4096;
+
+nix::ioctl_read_bad!(get_window_size, libc::TIOCGWINSZ, Winsize);
+nix::ioctl_write_ptr_bad!(set_window_size, libc::TIOCSWINSZ, Winsize);
+nix::ioctl_write_int_bad!(set_controlling_terminal, libc::TIOCSCTTY);
+
+pub struct Pty {
+    pub master: OwnedFd,
+    pub slave: OwnedFd
+}
+
+// Neither end may leak into the container command: the slave is dup'ed onto its stdio
+pub fn open_pty() -> Result<Pty, ErrorType> {
+    let pty = openpty(None, None).map_err(ErrorType::TtyError)?;
+    for fd in [&pty.master, &pty.slave] {
+        fcntl(fd.as_raw_fd(), FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)).map_err(ErrorType::TtyError)?;
+    }
+    resize(&pty.master);
+    Ok(Pty { master: pty.master, slave: pty.slave })
+}
+
+// Called in the child, once in its own root: the pty becomes the controlling terminal of a new session
+pub fn attach_terminal(slave: &OwnedFd) -> Result<(), ErrorType> {
+    setsid().map_err(ErrorType::TtyError)?;
+    unsafe { set_controlling_terminal(slave.as_raw_fd(), 0) }.map_err(ErrorType::TtyError)?;
+    Ok(())
+}
+
+// Only right before execve, so that setup errors are still reported on rucker's stderr
+pub fn redirect_stdio(slave: &OwnedFd) -> Result<(), ErrorType> {
+    for fd in 0..3 { dup2(slave.as_raw_fd(), fd).map_err(ErrorType::TtyError)?; }
+    Ok(())
+}
+
+// Gives the pty the size of the terminal rucker runs in, if any
+fn resize(master: &OwnedFd) {
+    let mut size = Winsize { ws_row: 0, ws_col: 0, ws_xpixel: 0, ws_ypixel: 0 };
+    if unsafe { get_window_size(stdout().as_raw_fd(), &mut size) }.is_err() { return; }
+    if let Err(err) = unsafe { set_window_size(master.as_raw_fd(), &size) } {
+        log::debug!("Failed to resize the pty: {:?}", err);
+    }
+}
+
+// The host terminal, switched to raw mode so that every key goes to the container, until dropped
+struct RawMode {
+    original: Termios
+}
+
+impl RawMode {
+    fn enable() -> Result<Option<RawMode>, ErrorType> {
+        if !isatty(stdin().as_raw_fd()).unwrap_or(false) { return Ok(None); }
+        let original = tcgetattr(stdin()).map_err(ErrorType::TtyError)?;
+        let mut raw = original.clone();
+        cfmakeraw(&mut raw);
+        tcsetattr(stdin(), SetArg::TCSANOW, &raw).map_err(ErrorType::TtyError)?;
+        Ok(Some(RawMode { original }))
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        if let Err(err) = tcsetattr(stdin(), SetArg::TCSANOW, &self.original) {
+            log::error!("Failed to restore the terminal: {:?}", err);
+        }
+    }
+}
+
+pub fn is_ready(fd: Option<&PollFd>) -> bool {
+    fd.and_then(|fd| fd.revents()).is_some_and(|revents| !revents.is_empty())
+}
+
+// Reads what is available from a pipe or a pty master, None once there is nothing more to read
+pub fn read_available(fd: BorrowedFd, buffer: &mut [u8]) -> Result<Option<usize>, Errno> {
+    match read(fd.as_raw_fd(), buffer) {
+        Ok(0) => Ok(None),
+        Ok(size) => Ok(Some(size)),
+        Err(Errno::EINTR) | Err(Errno::EAGAIN) => Ok(Some(0)),
+        // What reading the master returns once every slave is closed
+        Err(Errno::EIO) => Ok(None),
+        Err(err) => Err(err)
+    }
+}
+
+// Copies what is available from one fd to another, false once there is nothing more to read
+fn copy(from: BorrowedFd, to: BorrowedFd) -> Result<bool, ErrorType> {
+    let mut buffer = [0; BUFFER_SIZE];
+    let size = match read_available(from, &mut buffer).map_err(ErrorType::TtyError)? {
+        Some(size) => size,
+        None => return Ok(false)
+    };
+    let mut written = 0;
+    while written < size {
+        written += write(to, &buffer[written..size]).map_err(ErrorType::TtyError)?;
+    }
+    Ok(true)
+}
+
+impl ChildProcess {
+    // Like wait_forwarding, while proxying the pty of the child to rucker's stdio. SIGWINCH
+    // resizes the pty instead of being forwarded, the kernel signals the container itself then
+    pub fn wait_proxying(&mut self, master: &OwnedFd, interactive: bool) -> Result<i32, ErrorType> {
+        log::debug!("Waiting for child_proc (pid {}) to finish, proxying its terminal", self.pid);
+        let _raw_mode = if interactive { RawMode::enable()? } else { None };
+        // SIGWINCH is taken apart from the other forwarded signals, as it is not forwarded
+        let mut signals = forwarded_signals();
+        signals.remove(Signal::SIGWINCH);
+        let signal_fd = SignalFd::with_flags(&signals, SfdFlags::SFD_CLOEXEC).map_err(ErrorType::SignalError)?;
+        let mut winch = SigSet::empty();
+        winch.add(Signal::SIGWINCH);
+        let winch_fd = SignalFd::with_flags(&winch, SfdFlags::SFD_CLOEXEC).map_err(ErrorType::SignalError)?;
+        let (stdin, stdout) = (stdin(), stdout());
+        let (mut stdin_open, mut master_open) = (interactive, true);
+        loop {
+            let mut fds = vec![PollFd::new(winch_fd.as_fd(), PollFlags::POLLIN), PollFd::new(signal_fd.as_fd(), PollFlags::POLLIN)];
+            if master_open { fds.push(PollFd::new(master.as_fd(), PollFlags::POLLIN)); }
+            if stdin_open { fds.push(PollFd::new(stdin.as_fd(), PollFlags::POLLIN)); }
+            match poll(&mut fds, PollTimeout::NONE) {
+                Err(Errno::EINTR) => continue,
+                result => result.map_err(ErrorType::TtyError)?
+            };
+            let (winch_ready, signal_ready) = (is_ready(fds.first()), is_ready(fds.get(1)));
+            let master_ready = master_open && is_ready(fds.get(2));
+            let stdin_ready = stdin_open && is_ready(fds.last());
+            drop(fds);
+            if master_ready { master_open = copy(master.as_fd(), stdout.as_fd())?; }
+            if stdin_ready { stdin_open = copy(stdin.as_fd(), master.as_fd())?; }
+            if winch_ready {
+                winch_fd.read_signal().map_err(ErrorType::SignalError)?;
+                resize(master);
+            }
+            if !signal_ready { continue; }
+            if let Some(code) = self.handle_signal(&signal_fd)? {
+                // Output still buffered in the pty
+                while master_open && poll(&mut [PollFd::new(master.as_fd(), PollFlags::POLLIN)], PollTimeout::ZERO).unwrap_or(0) > 0 {
+                    master_open = copy(master.as_fd(), stdout.as_fd())?;
+                }
+                stdout.lock().flush().map_err(ErrorType::FileError)?;
+                return Ok(code);
+            }
+        }
+    }
+}
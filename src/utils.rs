@@ -1,7 +1,40 @@
+use crate::errors::ErrorType;
+
+use nix::sys::signal::Signal;
 use rand::Rng;
 
+use std::str::FromStr;
+
 pub fn random_hex_string() -> String {
     let mut rng: rand::prelude::ThreadRng = rand::thread_rng();
     let rand_bytes: [u8; 6] = rng.r#gen();
-    hex::encode(&rand_bytes)
+    hex::encode(rand_bytes)
+}
+
+// Accepts a number of bytes, or of kilobytes, megabytes... with a suffix: "512k", "1g"
+pub fn parse_size(size: &str) -> Result<u64, ErrorType> {
+    let invalid = || ErrorType::InvalidArgumentError(format!("invalid size {:?}, expected e.g. 512m or 1g", size));
+    let lowercase = size.trim().to_lowercase();
+    let number = lowercase.trim_end_matches('b');
+    let (number, unit) = match number.chars().last() {
+        Some('k') => (&number[..number.len() - 1], 1 << 10),
+        Some('m') => (&number[..number.len() - 1], 1 << 20),
+        Some('g') => (&number[..number.len() - 1], 1 << 30),
+        Some('t') => (&number[..number.len() - 1], 1 << 40),
+        _ => (number, 1)
+    };
+    number.parse::<u64>().ok().and_then(|number| number.checked_mul(unit)).ok_or_else(invalid)
+}
+
+// Accepts "SIGTERM", "TERM" or "15"
+pub fn parse_signal(signal: &str) -> Result<Signal, ErrorType> {
+    let parsed = match signal.parse::<i32>() {
+        Ok(number) => Signal::try_from(number).ok(),
+        Err(_) => {
+            let name = signal.to_uppercase();
+            let name = if name.starts_with("SIG") { name } else { format!("SIG{}", name) };
+            Signal::from_str(&name).ok()
+        }
+    };
+    parsed.ok_or_else(|| ErrorType::InvalidArgumentError(format!("unknown signal {}", signal)))
 }
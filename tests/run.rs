@@ -0,0 +1,74 @@
+// Runs containers with the rucker binary, which takes root: skipped otherwise
+use nix::unistd::Uid;
+
+use std::fs::{create_dir_all, remove_dir_all, remove_file, write};
+use std::path::PathBuf;
+use std::process::Command;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+const RUCKER: &str = env!("CARGO_BIN_EXE_rucker");
+
+fn rucker_run(options: &[&str], command: &[&str]) -> Option<i32> {
+    if !Uid::effective().is_root() {
+        eprintln!("Skipped, running containers takes root");
+        return None;
+    }
+    let status = Command::new(RUCKER).arg("run").args(options).arg("--").args(command)
+        .status().expect("Failed to run rucker");
+    Some(status.code().expect("rucker was killed by a signal"))
+}
+
+#[test]
+fn non_root_container_starts() {
+    let code = rucker_run(&["-u", "1000", "--network", "none", "-m", "/"], &["sh", "-c", "test \"$(id -u)\" = 1000"]);
+    assert!(code.is_none_or(|code| code == 0), "exited with {:?}", code);
+}
+
+#[test]
+fn exec_in_non_root_container() {
+    if !Uid::effective().is_root() { return eprintln!("Skipped, running containers takes root"); }
+    let output = Command::new(RUCKER).args(["run", "-d", "-u", "1000", "--network", "none", "-m", "/", "--", "sleep", "10"])
+        .output().expect("Failed to run rucker");
+    assert!(output.status.success(), "run -d exited with {:?}", output.status);
+    let id = String::from_utf8(output.stdout).unwrap().trim().to_string();
+    let status = Command::new(RUCKER).args(["exec", &id, "--", "sh", "-c", "test \"$(id -u)\" = 1000"])
+        .status().expect("Failed to run rucker exec");
+    let _ = Command::new(RUCKER).args(["delete", "--force", &id]).status();
+    assert!(status.success(), "exec exited with {:?}", status);
+}
+
+// A rootfs holding nothing but a static binary exiting with 7, None without a C compiler
+fn static_rootfs(name: &str) -> Option<PathBuf> {
+    let rootfs = std::env::temp_dir().join(format!("rucker-test-{}-{}", name, std::process::id()));
+    create_dir_all(&rootfs).expect("Failed to create the rootfs");
+    let source = rootfs.with_extension("c");
+    write(&source, "int main(void) { return 7; }\n").expect("Failed to write the C source");
+    let compiled = Command::new("cc").arg("-static").arg("-o").arg(rootfs.join("exit7")).arg(&source).status();
+    let _ = remove_file(&source);
+    compiled.is_ok_and(|status| status.success()).then_some(rootfs)
+}
+
+#[test]
+fn init_runs_in_static_rootfs() {
+    let Some(rootfs) = static_rootfs("init") else { return eprintln!("Skipped, no static C toolchain"); };
+    let code = rucker_run(&["--init", "--network", "none", "-m", rootfs.to_str().unwrap()], &["/exit7"]);
+    let _ = remove_dir_all(&rootfs);
+    assert!(code.is_none_or(|code| code == 7), "exited with {:?}", code);
+}
+
+// The container counts as started once the init is running, not when the workload exits
+#[test]
+fn init_keeps_long_workload_running() {
+    if !Uid::effective().is_root() { return eprintln!("Skipped, running containers takes root"); }
+    let started = Instant::now();
+    let output = Command::new(RUCKER).args(["run", "-d", "--init", "--network", "none", "-m", "/", "--", "sleep", "60"])
+        .output().expect("Failed to run rucker");
+    assert!(output.status.success(), "run -d exited with {:?}", output.status);
+    assert!(started.elapsed() < Duration::from_secs(10), "run -d took {:?}", started.elapsed());
+    let id = String::from_utf8(output.stdout).unwrap().trim().to_string();
+    sleep(Duration::from_secs(5));
+    let status = Command::new(RUCKER).args(["exec", &id, "--", "true"]).status().expect("Failed to run rucker exec");
+    let _ = Command::new(RUCKER).args(["delete", "--force", &id]).status();
+    assert!(status.success(), "exec exited with {:?}", status);
+}